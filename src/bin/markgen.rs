@@ -13,6 +13,10 @@ use getopts::Options;
 #[cfg(feature = "getopts")]
 use markov::Chain;
 
+/// The number of duplicate phrases in a row after which `-d` gives up on finding new ones.
+#[cfg(feature = "getopts")]
+const DISTINCT_ATTEMPTS: usize = 1000;
+
 #[cfg(all(feature = "getopts", not(test)))]
 fn main() {
     markov_gen(args().collect())
@@ -31,7 +35,8 @@ fn main() {
 /// into the chain. Additionally, the argument `-n #` is supported to specify the number of phrases
 /// to be generated. This number must be a positive, non-zero integer. `-o #` is also supported to
 /// specify the order of Markov chain to be used. Note `-o` must be specified before any file
-/// names and must also be a positive, non-zero integer.
+/// names and must also be a positive, non-zero integer. With `-d`, every phrase is distinct, and
+/// fewer phrases than requested are generated if the chain seems to run out of new ones.
 ///
 /// Some valid usages of this function:
 /// `markov_gen(vec!["test".to_owned()])`
@@ -61,6 +66,11 @@ fn markov_gen(args: Vec<String>) -> Vec<String> {
         "save the Markov chain to the specified path",
         "PATH",
     );
+    opts.optflag(
+        "d",
+        "distinct",
+        "generate distinct phrases, possibly fewer than requested",
+    );
     opts.optflag("h", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -95,7 +105,14 @@ fn markov_gen(args: Vec<String>) -> Vec<String> {
         if let Some(path) = matches.opt_str("s") {
             chain.save(path).unwrap();
         }
-        chain.str_iter_for(count).collect()
+        if matches.opt_present("d") {
+            chain
+                .str_iter_distinct(DISTINCT_ATTEMPTS)
+                .take(count)
+                .collect()
+        } else {
+            chain.str_iter_for(count).collect()
+        }
     }
 }
 
@@ -140,6 +157,22 @@ mod test {
         )
     }
 
+    #[test]
+    fn gen_distinct() {
+        let phrases = markov_gen(vec![
+            "markgen".to_owned(),
+            "test".to_owned(),
+            "-d".to_owned(),
+            "-n".to_owned(),
+            "1000".to_owned(),
+        ]);
+        assert!(!phrases.is_empty() && phrases.len() < 1000);
+        let mut sorted = phrases.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), phrases.len());
+    }
+
     #[test]
     #[should_panic(expected = "No files were fed into the chain.")]
     fn gen_invalid_no_files() {
@@ -0,0 +1,347 @@
+//! A chain that many threads can feed and generate from at the same time.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::{RwLock, RwLockReadGuard};
+
+use rand::{thread_rng, Rng};
+
+use super::ends::{self, Ends};
+use super::{Chain, Chainable, States, Token, Vocabulary};
+
+/// The number of shards of a chain created by `ConcurrentChain::of_order`.
+const DEFAULT_SHARDS: usize = 64;
+
+/// The transitions of the contexts that hash to the same shard.
+type Shard<T> = HashMap<Vec<Token<T>>, States<T>>;
+
+/// A Markov chain that can be fed from many threads at once, for models that must be updated live
+/// while they are used.
+///
+/// The contexts of the chain are split into shards by their hash, each behind its own lock, so
+/// that feeds that touch different shards proceed in parallel. A feed locks every shard it touches
+/// before changing any of them, in the same order every time, which rules out deadlocks.
+/// Generation only locks the shard of the current context for reading at each step, so it waits
+/// for at most one feed at a time. Every step sees either all or none of each feed, but a
+/// generated sequence may combine collections fed while it was being generated.
+///
+/// Unlike a `Chain`, which stores every token once and refers to it by id, the shards key their
+/// contexts on the tokens themselves, so that feeding never has to look up ids under a shared
+/// lock. This takes more memory when tokens are large.
+///
+/// Generation samples in proportion to the counts. To use the other features of `Chain`, such as
+/// smoothing or sampling settings, take a `snapshot` or convert it into a `Chain`, which keeps
+/// those settings.
+///
+/// ```
+/// use std::thread;
+/// use markov::{Chain, ConcurrentChain};
+///
+/// let chain = ConcurrentChain::new();
+/// thread::scope(|scope| {
+///     scope.spawn(|| chain.feed_str("I like cats"));
+///     scope.spawn(|| chain.feed_str("I like dogs"));
+/// });
+/// assert!(chain.generate_str().starts_with("I like"));
+/// let chain: Chain<String> = chain.into();
+/// ```
+pub struct ConcurrentChain<T: Chainable> {
+    settings: Chain<T>,
+    hasher: RandomState,
+    shards: Vec<RwLock<Shard<T>>>,
+    /// Every token in the order it was first fed, with the counts left at zero.
+    vocabulary: RwLock<Vocabulary<T>>,
+    /// Whether some contexts may never lead to the end of a sequence. Sequences fed into this
+    /// chain always end, so this is only the case for chains converted from one fed with streams.
+    endless: bool,
+}
+
+impl<T> Default for ConcurrentChain<T>
+where
+    T: Chainable,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> ConcurrentChain<T>
+where
+    T: Chainable,
+{
+    /// Constructs a new concurrent Markov chain.
+    pub fn new() -> ConcurrentChain<T> {
+        Self::of_order(1)
+    }
+
+    /// Creates a new concurrent Markov chain of the specified order.
+    pub fn of_order(order: usize) -> ConcurrentChain<T> {
+        Self::with_shards(order, DEFAULT_SHARDS)
+    }
+
+    /// Creates a new concurrent Markov chain of the specified order, with its contexts split into
+    /// the given number of shards. More shards let more threads feed the chain at once.
+    pub fn with_shards(order: usize, shards: usize) -> ConcurrentChain<T> {
+        Self::from_chain(Chain::of_order(order), shards)
+    }
+
+    /// Distributes the contents of the given chain over the given number of shards.
+    fn from_chain(chain: Chain<T>, shards: usize) -> ConcurrentChain<T> {
+        assert!(shards != 0);
+        let endless = ends::endless(chain.map.keys().cloned().collect(), |context| {
+            let states = chain.map.get(context).filter(|states| !states.is_empty())?;
+            states
+                .iter()
+                .map(|&(token, _)| {
+                    let mut following = context[1..].to_vec();
+                    following.push(Some(token?));
+                    Some(following)
+                })
+                .collect()
+        });
+        let mut concurrent = ConcurrentChain {
+            settings: chain.settings(),
+            hasher: RandomState::new(),
+            shards: (0..shards).map(|_| RwLock::new(HashMap::new())).collect(),
+            vocabulary: RwLock::new(Vocabulary::new()),
+            endless: !endless.is_empty(),
+        };
+        for (context, states) in chain.transitions() {
+            let shard = concurrent.shard(&context);
+            concurrent.shards[shard]
+                .get_mut()
+                .unwrap()
+                .insert(context, states);
+        }
+        let vocabulary = concurrent.vocabulary.get_mut().unwrap();
+        for (token, _) in chain.vocabulary.iter() {
+            vocabulary.add(token.clone(), 0);
+        }
+        concurrent
+    }
+
+    /// Gets the index of the shard that holds the given context.
+    fn shard(&self, context: &[Token<T>]) -> usize {
+        (self.hasher.hash_one(context) % self.shards.len() as u64) as usize
+    }
+
+    /// Locks every shard for reading, in order.
+    fn read_all(&self) -> Vec<RwLockReadGuard<'_, Shard<T>>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap())
+            .collect()
+    }
+
+    /// Gets the order of the chain, i.e. the number of previous tokens used for each mapping.
+    pub fn order(&self) -> usize {
+        self.settings.order
+    }
+
+    /// Determines whether or not the chain is empty. A chain is considered empty if nothing has
+    /// been fed into it.
+    pub fn is_empty(&self) -> bool {
+        self.read_all()
+            .iter()
+            .all(|shard| shard.values().all(States::is_empty))
+    }
+
+    /// Feeds the chain a collection of tokens, as in `Chain::feed`. Other threads can feed the
+    /// chain at the same time.
+    pub fn feed<S: AsRef<[T]>>(&self, tokens: S) -> &ConcurrentChain<T> {
+        let tokens = tokens.as_ref();
+        if tokens.is_empty() {
+            return self;
+        }
+        let order = self.order();
+        let toks = self.settings.pad(tokens);
+        let mut windows: Vec<_> = toks
+            .windows(order + 1)
+            .map(|p| (self.shard(&p[..order]), p))
+            .collect();
+        // The sort is stable, so the windows of each shard stay in the order they were fed.
+        windows.sort_by_key(|&(shard, _)| shard);
+
+        let mut guards = Vec::new();
+        for (shard, p) in windows {
+            if guards.last().map_or(true, |&(locked, _)| locked != shard) {
+                guards.push((shard, self.shards[shard].write().unwrap()));
+            }
+            let (_, ref mut guard) = *guards.last_mut().unwrap();
+            guard
+                .entry(p[..order].to_vec())
+                .or_default()
+                .add(p[order].clone(), 1);
+        }
+        // Most tokens have been seen before, so only take the vocabulary for writing if not.
+        let known = {
+            let vocabulary = self.vocabulary.read().unwrap();
+            toks[order..]
+                .iter()
+                .all(|token| vocabulary.position(token).is_some())
+        };
+        if !known {
+            let mut vocabulary = self.vocabulary.write().unwrap();
+            for token in &toks[order..] {
+                vocabulary.add(token.clone(), 0);
+            }
+        }
+        self
+    }
+
+    /// Generates a collection of tokens from the chain. Each step sees either all or none of each
+    /// collection fed at the same time.
+    pub fn generate(&self) -> Vec<T> {
+        self.generate_with_rng(&mut thread_rng())
+    }
+
+    /// Generates a collection of tokens from the chain using the given random number generator.
+    pub fn generate_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<T> {
+        let step = |context: &Vec<Token<T>>| {
+            let shard = self.shards[self.shard(context)].read().unwrap();
+            let states = shard.get(context).filter(|states| !states.is_empty())?;
+            let following = states
+                .iter()
+                .map(|(token, _)| {
+                    let mut following = context[1..].to_vec();
+                    following.push(Some(token.clone()?));
+                    Some(following)
+                })
+                .collect();
+            following
+        };
+        let mut ends = Ends::new();
+        let mut context = vec![None; self.order()];
+        let mut ret = Vec::new();
+        loop {
+            // Stop rather than loop forever once the end of a sequence is out of reach.
+            if self.endless && !ends.reachable(&context, step) {
+                return ret;
+            }
+            let next = self.shards[self.shard(&context)]
+                .read()
+                .unwrap()
+                .get(&context)
+                .filter(|states| !states.is_empty())
+                .and_then(|states| states.next(rng));
+            match next {
+                Some(next) => {
+                    context.remove(0);
+                    context.push(Some(next.clone()));
+                    ret.push(next);
+                }
+                None => return ret,
+            }
+        }
+    }
+
+    /// Creates a plain chain with the contents and settings of this one, as they are at the time
+    /// of the call.
+    pub fn snapshot(&self) -> Chain<T> {
+        let shards = self.read_all();
+        let vocabulary = self.vocabulary.read().unwrap();
+        let contexts = shards.iter().flat_map(|shard| {
+            shard
+                .iter()
+                .map(|(context, states)| (context.clone(), states.clone()))
+        });
+        self.settings.assemble(contexts, &vocabulary)
+    }
+}
+
+impl ConcurrentChain<String> {
+    /// Feeds a string of text into the chain, as in `Chain::feed_str`.
+    pub fn feed_str(&self, string: &str) -> &ConcurrentChain<String> {
+        self.feed(string.split(' ').map(|s| s.to_owned()).collect::<Vec<_>>())
+    }
+
+    /// Generates a random string of text.
+    pub fn generate_str(&self) -> String {
+        Chain::vec_to_string(self.generate())
+    }
+
+    /// Generates a random string of text using the given random number generator.
+    pub fn generate_str_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> String {
+        Chain::vec_to_string(self.generate_with_rng(rng))
+    }
+}
+
+impl<T> From<Chain<T>> for ConcurrentChain<T>
+where
+    T: Chainable,
+{
+    /// Converts a chain into a concurrent one with its contents and settings. The overlap index
+    /// and the end of the last stream fed into it are not kept.
+    fn from(chain: Chain<T>) -> ConcurrentChain<T> {
+        ConcurrentChain::from_chain(chain, DEFAULT_SHARDS)
+    }
+}
+
+impl<T> From<ConcurrentChain<T>> for Chain<T>
+where
+    T: Chainable,
+{
+    fn from(chain: ConcurrentChain<T>) -> Chain<T> {
+        let vocabulary = chain.vocabulary.into_inner().unwrap();
+        let contexts = chain
+            .shards
+            .into_iter()
+            .flat_map(|shard| shard.into_inner().unwrap());
+        chain.settings.assemble(contexts, &vocabulary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Chain, Smoothing};
+    use super::ConcurrentChain;
+    use std::thread;
+
+    #[test]
+    fn feed() {
+        let sequences: Vec<Vec<u16>> = (0..400u16)
+            .map(|i| (0..i % 9).map(|j| (i + j * j) % 17).collect())
+            .collect();
+        let concurrent = ConcurrentChain::with_shards(2, 4);
+        assert!(concurrent.is_empty());
+        thread::scope(|scope| {
+            for chunk in sequences.chunks(50) {
+                let concurrent = &concurrent;
+                scope.spawn(move || {
+                    for tokens in chunk {
+                        concurrent.feed(tokens);
+                        assert!(concurrent.generate().len() < 100);
+                    }
+                });
+            }
+        });
+        assert!(!concurrent.is_empty());
+        let mut fed = Chain::of_order(2);
+        for tokens in &sequences {
+            fed.feed(tokens);
+        }
+        assert_eq!(concurrent.snapshot(), fed);
+        assert_eq!(Chain::from(concurrent), fed);
+    }
+
+    #[test]
+    fn conversion() {
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_str("I like cats")
+            .feed_str("you like dogs")
+            .set_smoothing(Smoothing::WittenBell);
+        let concurrent = ConcurrentChain::from(chain.clone());
+        assert_eq!(concurrent.order(), 2);
+        concurrent.feed_str("I like birds");
+        for _ in 0..20 {
+            let s = concurrent.generate_str();
+            assert!(s.starts_with("I like") || s == "you like dogs", "{}", s);
+        }
+        chain.feed_str("I like birds");
+        let converted = Chain::from(concurrent);
+        assert_eq!(converted.smoothing(), Smoothing::WittenBell);
+        assert_eq!(converted, chain);
+    }
+}
@@ -0,0 +1,641 @@
+//! Generation of sequences that satisfy constraints, sampled from the distribution of a chain
+//! conditioned on the constraints rather than by filtering its output.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use rand::{thread_rng, Rng};
+
+use super::source::Source;
+use super::{choose, Chain, Chainable, FrozenChain, Id, Token};
+
+/// A transition out of a context: the next token (or `None` for the end of a sequence), its
+/// probability, and the index of the context it leads to.
+pub(crate) type Edge<'a, T> = (Option<&'a T>, f64, Option<usize>);
+
+/// The observed transitions of a chain, with its contexts numbered so that they can be indexed.
+pub(crate) struct Transitions<'a, T: Chainable + 'a> {
+    source: Source<'a, T>,
+    pub(crate) start: usize,
+    pub(crate) edges: Vec<Vec<Edge<'a, T>>>,
+    /// The number of each context of a plain chain. Those of a frozen chain are its rows.
+    index: HashMap<&'a [Token<Id>], usize>,
+}
+
+impl<'a, T> Transitions<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Numbers the contexts of the given chain and resolves the transitions between them.
+    pub(crate) fn new(source: Source<'a, T>) -> Transitions<'a, T> {
+        match source {
+            Source::Chain(chain) => Transitions::of_chain(chain),
+            Source::Frozen(frozen) => Transitions::of_frozen(frozen),
+        }
+    }
+
+    /// Numbers the contexts of a frozen chain by their rows.
+    fn of_frozen(frozen: &'a FrozenChain<T>) -> Transitions<'a, T> {
+        let mut edges = frozen.edges();
+        // A chain fed nothing but streams never starts a sequence.
+        let start = frozen.row(&vec![None; frozen.order()]).unwrap_or_else(|| {
+            edges.push(Vec::new());
+            edges.len() - 1
+        });
+        Transitions {
+            source: Source::Frozen(frozen),
+            start,
+            edges,
+            index: HashMap::new(),
+        }
+    }
+
+    /// Numbers the contexts of a plain chain in the order of its map.
+    fn of_chain(chain: &'a Chain<T>) -> Transitions<'a, T> {
+        let contexts: Vec<_> = chain.map.keys().collect();
+        let index: HashMap<_, _> = contexts
+            .iter()
+            .enumerate()
+            .map(|(i, context)| (&context[..], i))
+            .collect();
+        let edges = contexts
+            .iter()
+            .map(|context| {
+                let states = &chain.map[*context];
+                let sum = states.sum() as f64;
+                let mut next = context[1..].to_vec();
+                next.push(None);
+                states
+                    .iter()
+                    .filter_map(|(token, count)| {
+                        let p = *count as f64 / sum;
+                        match *token {
+                            None => Some((None, p, None)),
+                            Some(id) => {
+                                next[chain.order - 1] = Some(id);
+                                let target = *index.get(&next[..])?;
+                                Some((Some(chain.token(id)), p, Some(target)))
+                            }
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        Transitions {
+            source: Source::Chain(chain),
+            start: index[&vec![None; chain.order][..]],
+            edges,
+            index,
+        }
+    }
+
+    /// Gets the number of the given context, if it was seen.
+    pub(crate) fn find(&self, context: &[Token<Id>]) -> Option<usize> {
+        match self.source {
+            Source::Chain(_) => self.index.get(context).cloned(),
+            Source::Frozen(frozen) => frozen.row(context),
+        }
+    }
+}
+
+/// A sampler for sequences of an exact length, created by `Chain::exact_length` or
+/// `FrozenChain::exact_length`.
+///
+/// For every context of the chain and every `k` up to the length, it precomputes the probability
+/// of ending the sequence after exactly `k` more tokens. Each token is then sampled with its
+/// probability under the chain, weighted by the probability of ending on time from the context
+/// it leads to, which gives exactly the distribution of the chain conditioned on the length.
+pub struct ExactLength<'a, T: Chainable + 'a> {
+    transitions: Transitions<'a, T>,
+    /// The probability of ending after exactly `k` more tokens from each context, indexed by `k`.
+    table: Vec<Vec<f64>>,
+}
+
+impl<'a, T> ExactLength<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Gets the probability that the chain generates a sequence of exactly the requested length.
+    pub fn probability(&self) -> f64 {
+        self.table[self.table.len() - 1][self.transitions.start]
+    }
+
+    /// Generates a collection of tokens of exactly the requested length. This returns `None` if
+    /// the chain cannot generate a collection of that length.
+    pub fn generate(&self) -> Option<Vec<T>> {
+        self.generate_with_rng(&mut thread_rng())
+    }
+
+    /// Generates a collection of tokens of exactly the requested length, using the given random
+    /// number generator. This returns `None` if the chain cannot generate a collection of that
+    /// length.
+    pub fn generate_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<Vec<T>> {
+        let len = self.table.len() - 1;
+        let mut context = self.transitions.start;
+        let mut ret = Vec::with_capacity(len);
+        for remaining in (0..=len).rev() {
+            let edges = &self.transitions.edges[context];
+            let weights: Vec<_> = edges
+                .iter()
+                .map(|&(_, p, target)| match target {
+                    None if remaining == 0 => p,
+                    Some(target) if remaining > 0 => p * self.table[remaining - 1][target],
+                    _ => 0.0,
+                })
+                .collect();
+            let (token, _, target) = edges[choose(&weights, rng)?];
+            match (token, target) {
+                (Some(token), Some(target)) => {
+                    ret.push(token.clone());
+                    context = target;
+                }
+                _ => break,
+            }
+        }
+        Some(ret)
+    }
+}
+
+impl<'a> ExactLength<'a, String> {
+    /// Generates a random string of text of exactly the requested number of words. This returns
+    /// `None` if the chain cannot generate a string of that length.
+    pub fn generate_str(&self) -> Option<String> {
+        self.generate().map(Chain::vec_to_string)
+    }
+
+    /// Generates a random string of text of exactly the requested number of words, using the
+    /// given random number generator.
+    pub fn generate_str_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<String> {
+        self.generate_with_rng(rng).map(Chain::vec_to_string)
+    }
+}
+
+/// A finite automaton over tokens, used to constrain generation to the sequences it accepts.
+///
+/// Pairs of automata are themselves automata, accepting the sequences accepted by both.
+pub trait Automaton<T> {
+    /// The type of the states of the automaton.
+    type State: Clone + Eq + Hash;
+
+    /// Gets the state the automaton starts in.
+    fn start(&self) -> Self::State;
+
+    /// Gets the state reached by reading the given token in the given state, or `None` if no
+    /// sequence containing this transition can be accepted.
+    fn next(&self, state: &Self::State, token: &T) -> Option<Self::State>;
+
+    /// Determines whether the automaton accepts the sequences that end in the given state.
+    fn is_accepting(&self, state: &Self::State) -> bool;
+}
+
+impl<T, A, B> Automaton<T> for (A, B)
+where
+    A: Automaton<T>,
+    B: Automaton<T>,
+{
+    type State = (A::State, B::State);
+
+    fn start(&self) -> Self::State {
+        (self.0.start(), self.1.start())
+    }
+
+    fn next(&self, state: &Self::State, token: &T) -> Option<Self::State> {
+        Some((self.0.next(&state.0, token)?, self.1.next(&state.1, token)?))
+    }
+
+    fn is_accepting(&self, state: &Self::State) -> bool {
+        self.0.is_accepting(&state.0) && self.1.is_accepting(&state.1)
+    }
+}
+
+/// A state of a `Dfa`.
+#[derive(Clone, Debug)]
+struct DfaState<T: Chainable> {
+    accepting: bool,
+    transitions: HashMap<T, usize>,
+    otherwise: Option<usize>,
+}
+
+/// A [deterministic finite automaton](https://en.wikipedia.org/wiki/Deterministic_finite_automaton)
+/// over tokens. States are numbered in the order they are added, starting from the start state
+/// `0`. Every state may have a transition for specific tokens, and a transition for all other
+/// tokens; a token with neither is rejected.
+///
+/// ```
+/// use markov::{Chain, Dfa};
+///
+/// let mut chain = Chain::new();
+/// chain.feed_str("I like cats").feed_str("I like dogs").feed_str("you like dogs");
+/// let constraint = (
+///     Dfa::containing("like".to_owned()),
+///     Dfa::excluding(vec!["dogs".to_owned()]),
+/// );
+/// let sampler = chain.constrained(&constraint, 10);
+/// assert!(sampler.generate_str().unwrap().ends_with("like cats"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Dfa<T: Chainable> {
+    states: Vec<DfaState<T>>,
+}
+
+impl<T> Dfa<T>
+where
+    T: Chainable,
+{
+    /// Creates an automaton with only a start state, which has no transitions.
+    pub fn new(accepting: bool) -> Dfa<T> {
+        let mut dfa = Dfa { states: Vec::new() };
+        dfa.add_state(accepting);
+        dfa
+    }
+
+    /// Creates an automaton that accepts the sequences that contain the given token.
+    pub fn containing(token: T) -> Dfa<T> {
+        let mut dfa = Dfa::new(false);
+        let found = dfa.add_state(true);
+        dfa.add_transition(0, token, found)
+            .set_otherwise(0, Some(0))
+            .set_otherwise(found, Some(found));
+        dfa
+    }
+
+    /// Creates an automaton that accepts the sequences that contain none of the given tokens.
+    pub fn excluding<I: IntoIterator<Item = T>>(tokens: I) -> Dfa<T> {
+        let mut dfa = Dfa::new(true);
+        // A state without transitions rejects every token, and so every sequence reaching it.
+        let rejected = dfa.add_state(false);
+        dfa.set_otherwise(0, Some(0));
+        for token in tokens {
+            dfa.add_transition(0, token, rejected);
+        }
+        dfa
+    }
+
+    /// Adds a state to the automaton, returning its number.
+    pub fn add_state(&mut self, accepting: bool) -> usize {
+        self.states.push(DfaState {
+            accepting,
+            transitions: HashMap::new(),
+            otherwise: None,
+        });
+        self.states.len() - 1
+    }
+
+    /// Adds a transition reading the given token from one state to another.
+    pub fn add_transition(&mut self, from: usize, token: T, to: usize) -> &mut Dfa<T> {
+        assert!(to < self.states.len());
+        self.states[from].transitions.insert(token, to);
+        self
+    }
+
+    /// Sets the transition taken from the given state for tokens without a transition of their
+    /// own. `None` rejects such tokens.
+    pub fn set_otherwise(&mut self, from: usize, to: Option<usize>) -> &mut Dfa<T> {
+        assert!(to.map_or(true, |to| to < self.states.len()));
+        self.states[from].otherwise = to;
+        self
+    }
+}
+
+impl<T> Automaton<T> for Dfa<T>
+where
+    T: Chainable,
+{
+    type State = usize;
+
+    fn start(&self) -> usize {
+        0
+    }
+
+    fn next(&self, state: &usize, token: &T) -> Option<usize> {
+        let state = &self.states[*state];
+        state.transitions.get(token).cloned().or(state.otherwise)
+    }
+
+    fn is_accepting(&self, state: &usize) -> bool {
+        self.states[*state].accepting
+    }
+}
+
+/// A pair of a context of the chain and a state of the automaton, reached after a given number of
+/// tokens.
+struct Node<'a, T: 'a> {
+    /// The probability of ending the sequence here, or zero if the automaton would reject it.
+    end: f64,
+    /// The tokens that can follow, each with its probability and the node it leads to.
+    children: Vec<(&'a T, f64, usize)>,
+    /// The probability of generating an accepted sequence from here within the length limit.
+    value: f64,
+}
+
+/// A sampler for sequences accepted by an automaton, created by `Chain::constrained` or
+/// `FrozenChain::constrained`.
+///
+/// It explores every pair of a context of the chain and a state of the automaton that can be
+/// reached within the length limit, and computes the probability of reaching an accepted end
+/// from each of them. Each token is then sampled with its probability under the chain, weighted
+/// by the probability of acceptance from where it leads, which gives exactly the distribution of
+/// the chain conditioned on acceptance.
+pub struct Constrained<'a, T: Chainable + 'a> {
+    nodes: Vec<Node<'a, T>>,
+}
+
+impl<'a, T> Constrained<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Gets the probability that the chain generates a sequence that satisfies the constraint.
+    pub fn probability(&self) -> f64 {
+        self.nodes[0].value
+    }
+
+    /// Generates a collection of tokens that satisfies the constraint. This returns `None` if the
+    /// chain cannot generate such a collection.
+    pub fn generate(&self) -> Option<Vec<T>> {
+        self.generate_with_rng(&mut thread_rng())
+    }
+
+    /// Generates a collection of tokens that satisfies the constraint, using the given random
+    /// number generator. This returns `None` if the chain cannot generate such a collection.
+    pub fn generate_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<Vec<T>> {
+        let mut node = &self.nodes[0];
+        let mut ret = Vec::new();
+        loop {
+            let mut weights = vec![node.end];
+            weights.extend(
+                node.children
+                    .iter()
+                    .map(|&(_, p, child)| p * self.nodes[child].value),
+            );
+            match choose(&weights, rng)? {
+                0 => return Some(ret),
+                i => {
+                    let (token, _, child) = node.children[i - 1];
+                    ret.push(token.clone());
+                    node = &self.nodes[child];
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Constrained<'a, String> {
+    /// Generates a random string of text that satisfies the constraint. This returns `None` if the
+    /// chain cannot generate such a string.
+    pub fn generate_str(&self) -> Option<String> {
+        self.generate().map(Chain::vec_to_string)
+    }
+
+    /// Generates a random string of text that satisfies the constraint, using the given random
+    /// number generator.
+    pub fn generate_str_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<String> {
+        self.generate_with_rng(rng).map(Chain::vec_to_string)
+    }
+}
+
+impl<'a, T> Source<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Creates a sampler for collections of at most `max_tokens` tokens that are accepted by the
+    /// given automaton.
+    fn constrained<A: Automaton<T>>(self, automaton: &A, max_tokens: usize) -> Constrained<'a, T> {
+        let transitions = Transitions::new(self);
+        let mut nodes = Vec::new();
+        let mut states = Vec::new();
+        let mut layer = HashMap::new();
+        layer.insert((transitions.start, automaton.start()), 0);
+        states.push((transitions.start, automaton.start()));
+        nodes.push(Node {
+            end: 0.0,
+            children: Vec::new(),
+            value: 0.0,
+        });
+
+        // Explore every pair reachable within the limit, one layer of equal length at a time.
+        let mut current = 0..1;
+        for len in 0..=max_tokens {
+            let mut next_layer = HashMap::new();
+            for i in current.clone() {
+                let (context, ref state) = states[i].clone();
+                for &(token, p, target) in &transitions.edges[context] {
+                    match (token, target) {
+                        (None, _) if automaton.is_accepting(state) => nodes[i].end = p,
+                        (Some(token), Some(target)) if len < max_tokens => {
+                            let next = match automaton.next(state, token) {
+                                Some(next) => next,
+                                None => continue,
+                            };
+                            let child =
+                                *next_layer.entry((target, next.clone())).or_insert_with(|| {
+                                    states.push((target, next));
+                                    nodes.push(Node {
+                                        end: 0.0,
+                                        children: Vec::new(),
+                                        value: 0.0,
+                                    });
+                                    nodes.len() - 1
+                                });
+                            nodes[i].children.push((token, p, child));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            current = current.end..nodes.len();
+        }
+
+        // Children always come after their parents, so values can be computed in reverse.
+        for i in (0..nodes.len()).rev() {
+            let value = nodes[i].end
+                + nodes[i]
+                    .children
+                    .iter()
+                    .map(|&(_, p, child)| p * nodes[child].value)
+                    .sum::<f64>();
+            nodes[i].value = value;
+        }
+        Constrained { nodes }
+    }
+
+    /// Creates a sampler for collections of exactly `len` tokens that end naturally.
+    fn exact_length(self, len: usize) -> ExactLength<'a, T> {
+        let transitions = Transitions::new(self);
+        let mut table: Vec<Vec<f64>> = Vec::with_capacity(len + 1);
+        for k in 0..=len {
+            let row = transitions
+                .edges
+                .iter()
+                .map(|edges| {
+                    edges
+                        .iter()
+                        .map(|&(_, p, target)| match target {
+                            None if k == 0 => p,
+                            Some(target) if k > 0 => p * table[k - 1][target],
+                            _ => 0.0,
+                        })
+                        .sum()
+                })
+                .collect();
+            table.push(row);
+        }
+        ExactLength { transitions, table }
+    }
+}
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Creates a sampler for collections of at most `max_tokens` tokens that are accepted by the
+    /// given automaton, drawn from the observed transitions of the chain conditioned on their
+    /// acceptance, in the style of the constrained Markov processes of Pachet and Roy. This is much
+    /// faster than generating sequences until one is accepted. Building the sampler takes time
+    /// and space proportional to the number of reachable pairs of a context and an automaton
+    /// state at each length.
+    pub fn constrained<A: Automaton<T>>(
+        &self,
+        automaton: &A,
+        max_tokens: usize,
+    ) -> Constrained<'_, T> {
+        Source::Chain(self).constrained(automaton, max_tokens)
+    }
+
+    /// Creates a sampler for collections of exactly `len` tokens that end naturally, i.e. where
+    /// the end of the sequence was generated by the chain rather than forced. Sequences are drawn
+    /// from the observed transitions of the chain conditioned on their length, which is much
+    /// faster than generating sequences until one has the right length. Building the sampler
+    /// takes `O(len * n)` time and `O(len * m)` space, where `n` is the number of transitions and
+    /// `m` the number of contexts in the chain.
+    pub fn exact_length(&self, len: usize) -> ExactLength<'_, T> {
+        Source::Chain(self).exact_length(len)
+    }
+}
+
+impl<T> FrozenChain<T>
+where
+    T: Chainable,
+{
+    /// Creates a sampler for collections of at most `max_tokens` tokens that are accepted by the
+    /// given automaton, as in `Chain::constrained`.
+    pub fn constrained<A: Automaton<T>>(
+        &self,
+        automaton: &A,
+        max_tokens: usize,
+    ) -> Constrained<'_, T> {
+        Source::Frozen(self).constrained(automaton, max_tokens)
+    }
+
+    /// Creates a sampler for collections of exactly `len` tokens that end naturally, as in
+    /// `Chain::exact_length`.
+    pub fn exact_length(&self, len: usize) -> ExactLength<'_, T> {
+        Source::Frozen(self).exact_length(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Chain;
+    use super::{Automaton, Dfa};
+
+    #[test]
+    fn exact_length() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![1u8, 2, 3])
+            .feed(vec![1, 1, 2])
+            .feed(vec![3, 4, 5, 6]);
+        for len in 1..8 {
+            let sampler = chain.exact_length(len);
+            assert!(sampler.probability() > 0.0);
+            for _ in 0..20 {
+                let v = sampler.generate().unwrap();
+                assert_eq!(v.len(), len);
+                assert!(chain.log_likelihood(&v).is_finite());
+            }
+        }
+        assert_eq!(chain.exact_length(0).generate(), None);
+    }
+
+    #[test]
+    fn exact_length_probability() {
+        let mut chain = Chain::new();
+        chain.feed(vec![1u8, 1]).feed(vec![1]);
+        // After 1, the chain continues with probability 1/3 and ends with probability 2/3.
+        let expected = 1.0 / 3.0 * 2.0 / 3.0;
+        assert!((chain.exact_length(2).probability() - expected).abs() < 1e-12);
+        assert!((chain.freeze().unwrap().exact_length(2).probability() - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn exact_length_str() {
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_str("I like cats")
+            .feed_str("I like cats and dogs")
+            .feed_str("you like dogs");
+        let sampler = chain.exact_length(5);
+        for _ in 0..10 {
+            assert_eq!(sampler.generate_str().unwrap(), "I like cats and dogs");
+        }
+        assert_eq!(chain.exact_length(4).generate_str(), None);
+    }
+
+    #[test]
+    fn constrained() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![1u8, 2, 3])
+            .feed(vec![1, 4, 3])
+            .feed(vec![2, 4])
+            .feed(vec![3, 1]);
+        let constraint = (Dfa::containing(4), Dfa::excluding(vec![1]));
+        let sampler = chain.constrained(&constraint, 10);
+        assert!(sampler.probability() > 0.0);
+        for _ in 0..50 {
+            let v = sampler.generate().unwrap();
+            assert!(
+                v.len() <= 10 && v.contains(&4) && !v.contains(&1),
+                "{:?}",
+                v
+            );
+            assert!(chain.log_likelihood(&v).is_finite());
+        }
+        assert_eq!(chain.constrained(&Dfa::containing(5), 10).generate(), None);
+        assert_eq!(chain.constrained(&constraint, 1).generate(), None);
+    }
+
+    #[test]
+    fn constrained_probability() {
+        let mut chain = Chain::new();
+        chain.feed(vec![1u8, 1]).feed(vec![1]);
+        // Sequences of 1s of every length are accepted, so only the limit on the length matters.
+        let sampler = chain.constrained(&Dfa::containing(1), 2);
+        let expected = 2.0 / 3.0 + 1.0 / 3.0 * 2.0 / 3.0;
+        assert!((sampler.probability() - expected).abs() < 1e-12);
+        let frozen = chain.freeze().unwrap();
+        let sampler = frozen.constrained(&Dfa::containing(1), 2);
+        assert!((sampler.probability() - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn dfa() {
+        // Sequences alternating between 1 and 2, starting with 1.
+        let mut dfa = Dfa::new(true);
+        let odd = dfa.add_state(true);
+        dfa.add_transition(0, 1u8, odd).add_transition(odd, 2, 0);
+        assert_eq!(dfa.next(&0, &1), Some(odd));
+        assert_eq!(dfa.next(&0, &2), None);
+        let mut chain = Chain::new();
+        chain.feed(vec![1u8, 2, 1, 2, 2]).feed(vec![1, 1, 2, 1]);
+        let sampler = chain.constrained(&dfa, 20);
+        for _ in 0..20 {
+            let v = sampler.generate().unwrap();
+            assert!(
+                v.iter().enumerate().all(|(i, &t)| t == [1, 2][i % 2]),
+                "{:?}",
+                v
+            );
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Decoding of the most likely sequences of a chain, rather than random ones.
+
+use super::source::Source;
+use super::{Chain, Chainable, FrozenChain, Token};
+
+/// A partial sequence being extended by beam search.
+struct Beam<T> {
+    context: Vec<Token<T>>,
+    tokens: Vec<T>,
+    score: f64,
+}
+
+impl<'a, T> Source<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Gets the context following the given tokens at the start of a sequence, along with the
+    /// log-likelihood of reaching it.
+    fn prefix_context(self, prefix: &[T]) -> (Vec<Token<T>>, f64) {
+        let order = self.order();
+        let mut toks = self.settings().pad(prefix);
+        toks.pop();
+        let score = toks
+            .windows(order + 1)
+            .map(|p| self.probability(&p[0..order], p[order].as_ref()).ln())
+            .sum();
+        (toks[toks.len() - order..].to_vec(), score)
+    }
+
+    /// Completes the given prefix by always following the most likely successor.
+    fn greedy(self, prefix: &[T], max_tokens: usize) -> Option<(Vec<T>, f64)> {
+        let (mut context, mut score) = self.prefix_context(prefix);
+        let mut tokens = prefix.to_vec();
+        for _ in 0..=max_tokens {
+            let mut best = None;
+            for (next, p) in self.successors(&context) {
+                if p > best.map_or(0.0, |(_, q)| q) {
+                    best = Some((next, p));
+                }
+            }
+            let (next, p) = best.filter(|_| score.is_finite())?;
+            score += p.ln();
+            match next {
+                Some(next) => {
+                    tokens.push(next.clone());
+                    context.remove(0);
+                    context.push(Some(next.clone()));
+                }
+                None => return Some((tokens, score)),
+            }
+        }
+        None
+    }
+
+    /// Finds the most likely ways to complete the given prefix using a beam search.
+    fn beam_search(self, prefix: &[T], width: usize, max_tokens: usize) -> Vec<(Vec<T>, f64)> {
+        assert!(width > 0);
+        let (context, score) = self.prefix_context(prefix);
+        let mut beams = vec![Beam {
+            context,
+            tokens: prefix.to_vec(),
+            score,
+        }];
+        beams.retain(|beam| beam.score.is_finite());
+        let mut finished: Vec<(Vec<T>, f64)> = Vec::new();
+
+        for step in 0..=max_tokens {
+            let mut candidates = Vec::new();
+            for beam in &beams {
+                for (next, p) in self.successors(&beam.context) {
+                    if p <= 0.0 {
+                        continue;
+                    }
+                    let score = beam.score + p.ln();
+                    match next {
+                        None => finished.push((beam.tokens.clone(), score)),
+                        Some(_) if step == max_tokens => (),
+                        Some(next) => candidates.push((beam, next, score)),
+                    }
+                }
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+            candidates.truncate(width);
+
+            // Scores only ever decrease, so no beam can beat a full set of finished sequences
+            // that are all more likely than it is.
+            finished.sort_by(|a, b| b.1.total_cmp(&a.1));
+            finished.truncate(width);
+            if finished.len() == width
+                && candidates
+                    .first()
+                    .map_or(true, |&(_, _, score)| score <= finished[width - 1].1)
+            {
+                break;
+            }
+
+            beams = candidates
+                .into_iter()
+                .map(|(beam, next, score)| {
+                    let mut context = beam.context[1..].to_vec();
+                    context.push(Some(next.clone()));
+                    let mut tokens = beam.tokens.clone();
+                    tokens.push(next.clone());
+                    Beam {
+                        context,
+                        tokens,
+                        score,
+                    }
+                })
+                .collect();
+            if beams.is_empty() {
+                break;
+            }
+        }
+        finished
+    }
+}
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Completes the given prefix by always following the most likely successor, where an empty
+    /// prefix starts a new sequence. The returned sequence includes the prefix and is paired with
+    /// its log-likelihood, as computed by `log_likelihood`. This returns `None` if the prefix
+    /// cannot be continued, or if the sequence does not end within `max_tokens` more tokens.
+    ///
+    /// Unlike `generate_from_prefix`, which continues the prefix from anywhere within a sequence,
+    /// the prefix is taken to be the start of a sequence, so that the whole result can be scored.
+    pub fn greedy(&self, prefix: &[T], max_tokens: usize) -> Option<(Vec<T>, f64)> {
+        Source::Chain(self).greedy(prefix, max_tokens)
+    }
+
+    /// Finds the most likely ways to complete the given prefix using a
+    /// [beam search](https://en.wikipedia.org/wiki/Beam_search) that keeps the `width` most likely
+    /// partial sequences at every step, where an empty prefix starts a new sequence. This returns
+    /// up to `width` complete sequences of at most `max_tokens` more tokens, from most to least
+    /// likely. Every sequence includes the prefix and is paired with its log-likelihood, as
+    /// computed by `log_likelihood`.
+    ///
+    /// As with `greedy`, the prefix is taken to be the start of a sequence.
+    pub fn beam_search(&self, prefix: &[T], width: usize, max_tokens: usize) -> Vec<(Vec<T>, f64)> {
+        Source::Chain(self).beam_search(prefix, width, max_tokens)
+    }
+}
+
+impl<T> FrozenChain<T>
+where
+    T: Chainable,
+{
+    /// Completes the given prefix by always following the most likely successor, as in
+    /// `Chain::greedy`.
+    pub fn greedy(&self, prefix: &[T], max_tokens: usize) -> Option<(Vec<T>, f64)> {
+        Source::Frozen(self).greedy(prefix, max_tokens)
+    }
+
+    /// Finds the most likely ways to complete the given prefix using a beam search, as in
+    /// `Chain::beam_search`.
+    pub fn beam_search(&self, prefix: &[T], width: usize, max_tokens: usize) -> Vec<(Vec<T>, f64)> {
+        Source::Frozen(self).beam_search(prefix, width, max_tokens)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Chain;
+
+    #[test]
+    fn greedy() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![1u8, 2, 3])
+            .feed(vec![1, 2, 3])
+            .feed(vec![1, 4])
+            .feed(vec![2, 3, 5]);
+        let (tokens, score) = chain.greedy(&[], 10).unwrap();
+        assert_eq!(tokens, vec![1, 2, 3]);
+        assert!((score - chain.log_likelihood(&tokens)).abs() < 1e-12);
+        assert_eq!(chain.greedy(&[2], 10).unwrap().0, vec![2, 3]);
+        assert_eq!(chain.greedy(&[1], 1), None);
+        assert_eq!(chain.greedy(&[9], 10), None);
+        assert_eq!(
+            chain.freeze().unwrap().greedy(&[], 10),
+            chain.greedy(&[], 10)
+        );
+    }
+
+    #[test]
+    fn beam_search() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![1u8, 2, 3])
+            .feed(vec![1, 2, 3])
+            .feed(vec![1, 4])
+            .feed(vec![2, 3, 5]);
+        let results = chain.beam_search(&[], 3, 10);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, vec![1, 2, 3]);
+        for window in results.windows(2) {
+            assert!(window[0].1 >= window[1].1);
+        }
+        for (tokens, score) in &results {
+            assert!((score - chain.log_likelihood(tokens)).abs() < 1e-12);
+        }
+        let results = chain.beam_search(&[1], 5, 1);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, vec![1, 4]);
+        assert!(chain.beam_search(&[9], 5, 10).is_empty());
+        assert_eq!(
+            chain.freeze().unwrap().beam_search(&[], 3, 10),
+            chain.beam_search(&[], 3, 10)
+        );
+    }
+}
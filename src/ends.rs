@@ -0,0 +1,173 @@
+//! Detection of contexts from which the end of a sequence can never be reached.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// Keeps track of which states of a chain can still lead to the end of a sequence, so that
+/// generation can stop on chains that contain loops without an end, such as those fed with
+/// `feed_stream` or `feed_cycle`, instead of running forever.
+///
+/// States are explored as they are asked about, and the answers are remembered for the rest of
+/// the generation.
+pub(crate) struct Ends<S> {
+    reaching: HashSet<S>,
+    endless: HashSet<S>,
+}
+
+impl<S> Ends<S>
+where
+    S: Clone + Eq + Hash,
+{
+    /// Creates a tracker that knows nothing yet.
+    pub(crate) fn new() -> Ends<S> {
+        Ends {
+            reaching: HashSet::new(),
+            endless: HashSet::new(),
+        }
+    }
+
+    /// Determines whether the end of a sequence can be reached from the given state. `step` gets
+    /// the states that may follow a state, or `None` if the sequence may end right after it.
+    pub(crate) fn reachable<F>(&mut self, state: &S, mut step: F) -> bool
+    where
+        F: FnMut(&S) -> Option<Vec<S>>,
+    {
+        if self.reaching.contains(state) {
+            return true;
+        }
+        if self.endless.contains(state) {
+            return false;
+        }
+        // Search depth first, keeping the states that are left to visit after each one on the
+        // path, until a state that can end is found.
+        let mut visited = HashSet::new();
+        visited.insert(state.clone());
+        let mut path = match step(state) {
+            Some(next) => vec![(state.clone(), next)],
+            None => {
+                self.reaching.insert(state.clone());
+                return true;
+            }
+        };
+        while let Some(next) = path.last_mut().map(|&mut (_, ref mut next)| next.pop()) {
+            let next = match next {
+                Some(next) => next,
+                None => {
+                    path.pop();
+                    continue;
+                }
+            };
+            if self.endless.contains(&next) || !visited.insert(next.clone()) {
+                continue;
+            }
+            if self.reaching.contains(&next) {
+                break;
+            }
+            match step(&next) {
+                Some(after) => path.push((next, after)),
+                None => {
+                    self.reaching.insert(next);
+                    break;
+                }
+            }
+        }
+        if path.is_empty() {
+            // Every state reachable from this one was visited without finding an end.
+            self.endless.extend(visited);
+            return false;
+        }
+        self.reaching
+            .extend(path.into_iter().map(|(state, _)| state));
+        true
+    }
+}
+
+/// Finds every state from which the end of a sequence can never be reached, among the given
+/// states and those that can follow them. `step` gets the states that may follow a state, or
+/// `None` if the sequence may end right after it.
+pub(crate) fn endless<S, F>(mut states: Vec<S>, mut step: F) -> HashSet<S>
+where
+    S: Clone + Eq + Hash,
+    F: FnMut(&S) -> Option<Vec<S>>,
+{
+    // Number every state and link each to the states before it.
+    let mut index: HashMap<_, _> = states
+        .iter()
+        .enumerate()
+        .map(|(i, state)| (state.clone(), i))
+        .collect();
+    let mut before = vec![Vec::new(); states.len()];
+    let mut reaching = Vec::new();
+    let mut i = 0;
+    while i < states.len() {
+        match step(&states[i]) {
+            Some(following) => {
+                for state in following {
+                    let j = *index.entry(state.clone()).or_insert(states.len());
+                    if j == states.len() {
+                        states.push(state);
+                        before.push(Vec::new());
+                    }
+                    before[j].push(i);
+                }
+            }
+            None => reaching.push(i),
+        }
+        i += 1;
+    }
+
+    // Walk the steps backwards from the states that can end.
+    let mut reached = vec![false; states.len()];
+    for &i in &reaching {
+        reached[i] = true;
+    }
+    while let Some(j) = reaching.pop() {
+        for &i in &before[j] {
+            if !reached[i] {
+                reached[i] = true;
+                reaching.push(i);
+            }
+        }
+    }
+    states
+        .into_iter()
+        .zip(reached)
+        .filter(|&(_, reached)| !reached)
+        .map(|(state, _)| state)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{endless, Ends};
+
+    #[test]
+    fn reachable() {
+        // 0 -> 1 -> 2 -> end, and 3 <-> 4 loop forever, while 5 may go either way.
+        let step = |&state: &u8| match state {
+            2 => None,
+            3 => Some(vec![4]),
+            4 => Some(vec![3]),
+            5 => Some(vec![1, 3]),
+            _ => Some(vec![state + 1]),
+        };
+        let mut ends = Ends::new();
+        assert!(ends.reachable(&0, step));
+        assert!(!ends.reachable(&3, step));
+        assert!(!ends.reachable(&4, step));
+        assert!(ends.reachable(&5, step));
+        assert!(ends.reachable(&1, step));
+    }
+
+    #[test]
+    fn endless_states() {
+        let endless = endless(vec![0u8, 5], |&state| match state {
+            2 => None,
+            3 => Some(vec![4]),
+            4 => Some(vec![3]),
+            5 => Some(vec![1, 3]),
+            _ => Some(vec![state + 1]),
+        });
+        assert_eq!(endless, [3, 4].iter().cloned().collect());
+    }
+}
@@ -0,0 +1,498 @@
+//! Generation of sequences that are filtered after the fact, by drawing from the chain until the
+//! output is acceptable.
+
+use std::collections::HashSet;
+use std::error;
+use std::fmt;
+use std::iter::Map;
+
+use rand::rngs::ThreadRng;
+use rand::{thread_rng, Rng};
+
+use super::source::Source;
+use super::{Chain, Chainable, FrozenChain};
+
+/// The error returned when generation gives up on finding an acceptable output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Exhausted {
+    /// The number of outputs that were generated and rejected.
+    pub attempts: usize,
+}
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no acceptable output in {} attempts", self.attempts)
+    }
+}
+
+impl error::Error for Exhausted {}
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Generates collections of tokens until one satisfies the given predicate, giving up after
+    /// `max_attempts` collections. Accepted collections follow the distribution of the chain
+    /// conditioned on the predicate.
+    pub fn generate_where<P>(&self, predicate: P, max_attempts: usize) -> Result<Vec<T>, Exhausted>
+    where
+        P: FnMut(&[T]) -> bool,
+    {
+        self.generate_where_with_rng(predicate, max_attempts, &mut thread_rng())
+    }
+
+    /// Generates collections of tokens until one satisfies the given predicate, giving up after
+    /// `max_attempts` collections, using the given random number generator.
+    pub fn generate_where_with_rng<P, R>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<Vec<T>, Exhausted>
+    where
+        P: FnMut(&[T]) -> bool,
+        R: Rng + ?Sized,
+    {
+        Source::Chain(self).generate_where(predicate, max_attempts, rng)
+    }
+
+    /// Produces an iterator over collections of tokens that satisfy the given predicate, allowing
+    /// up to `max_attempts` generated collections for each one. The iterator yields a single error
+    /// and ends once an item exhausts its attempts.
+    pub fn iter_where<P>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+    ) -> FilteredChainIterator<'_, T, P>
+    where
+        P: FnMut(&[T]) -> bool,
+    {
+        self.iter_where_with_rng(predicate, max_attempts, thread_rng())
+    }
+
+    /// Produces an iterator over collections of tokens that satisfy the given predicate, allowing
+    /// up to `max_attempts` generated collections for each one, using the given random number
+    /// generator.
+    pub fn iter_where_with_rng<P, R>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+        rng: R,
+    ) -> FilteredChainIterator<'_, T, P, R>
+    where
+        P: FnMut(&[T]) -> bool,
+        R: Rng,
+    {
+        Source::Chain(self).iter_where(predicate, max_attempts, rng)
+    }
+
+    /// Produces an iterator over distinct collections of tokens, which skips any collection it
+    /// has already yielded. It ends once `max_attempts` collections in a row were duplicates,
+    /// which suggests that the chain has nothing new left to generate.
+    pub fn iter_distinct(&self, max_attempts: usize) -> DistinctChainIterator<'_, T> {
+        self.iter_distinct_with_rng(max_attempts, thread_rng())
+    }
+
+    /// Produces an iterator over distinct collections of tokens that draws from the given random
+    /// number generator.
+    pub fn iter_distinct_with_rng<R: Rng>(
+        &self,
+        max_attempts: usize,
+        rng: R,
+    ) -> DistinctChainIterator<'_, T, R> {
+        Source::Chain(self).iter_distinct(max_attempts, rng)
+    }
+}
+
+impl Chain<String> {
+    /// Generates random strings of text until one satisfies the given predicate, giving up after
+    /// `max_attempts` strings.
+    pub fn generate_str_where<P>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+    ) -> Result<String, Exhausted>
+    where
+        P: FnMut(&str) -> bool,
+    {
+        self.generate_str_where_with_rng(predicate, max_attempts, &mut thread_rng())
+    }
+
+    /// Generates random strings of text until one satisfies the given predicate, giving up after
+    /// `max_attempts` strings, using the given random number generator.
+    pub fn generate_str_where_with_rng<P, R>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<String, Exhausted>
+    where
+        P: FnMut(&str) -> bool,
+        R: Rng + ?Sized,
+    {
+        Source::Chain(self).generate_str_where(predicate, max_attempts, rng)
+    }
+
+    /// Produces an iterator over distinct generated strings, as in `iter_distinct`.
+    pub fn str_iter_distinct(&self, max_attempts: usize) -> DistinctChainStringIterator<'_> {
+        self.str_iter_distinct_with_rng(max_attempts, thread_rng())
+    }
+
+    /// Produces an iterator over distinct generated strings that draws from the given random
+    /// number generator.
+    pub fn str_iter_distinct_with_rng<R: Rng>(
+        &self,
+        max_attempts: usize,
+        rng: R,
+    ) -> DistinctChainStringIterator<'_, R> {
+        let vec_to_string: fn(Vec<String>) -> String = Chain::vec_to_string;
+        self.iter_distinct_with_rng(max_attempts, rng)
+            .map(vec_to_string)
+    }
+}
+
+impl<'a, T> Source<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Generates collections of tokens until one satisfies the given predicate, giving up after
+    /// `max_attempts` collections.
+    pub(crate) fn generate_where<P, R>(
+        self,
+        mut predicate: P,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<Vec<T>, Exhausted>
+    where
+        P: FnMut(&[T]) -> bool,
+        R: Rng + ?Sized,
+    {
+        (0..max_attempts)
+            .map(|_| self.generate_with_rng(rng))
+            .find(|tokens| predicate(tokens))
+            .ok_or(Exhausted {
+                attempts: max_attempts,
+            })
+    }
+
+    /// Produces an iterator over collections of tokens that satisfy the given predicate.
+    fn iter_where<P, R>(
+        self,
+        predicate: P,
+        max_attempts: usize,
+        rng: R,
+    ) -> FilteredChainIterator<'a, T, P, R>
+    where
+        P: FnMut(&[T]) -> bool,
+        R: Rng,
+    {
+        FilteredChainIterator {
+            chain: self,
+            predicate,
+            max_attempts,
+            rng,
+            exhausted: false,
+        }
+    }
+
+    /// Produces an iterator over distinct collections of tokens.
+    fn iter_distinct<R: Rng>(self, max_attempts: usize, rng: R) -> DistinctChainIterator<'a, T, R> {
+        DistinctChainIterator {
+            chain: self,
+            seen: HashSet::new(),
+            max_attempts,
+            rng,
+        }
+    }
+}
+
+impl<'a> Source<'a, String> {
+    /// Generates random strings of text until one satisfies the given predicate, giving up after
+    /// `max_attempts` strings.
+    fn generate_str_where<P, R>(
+        self,
+        mut predicate: P,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<String, Exhausted>
+    where
+        P: FnMut(&str) -> bool,
+        R: Rng + ?Sized,
+    {
+        (0..max_attempts)
+            .map(|_| Chain::vec_to_string(self.generate_with_rng(rng)))
+            .find(|string| predicate(string))
+            .ok_or(Exhausted {
+                attempts: max_attempts,
+            })
+    }
+}
+
+impl<T> FrozenChain<T>
+where
+    T: Chainable,
+{
+    /// Generates collections of tokens until one satisfies the given predicate, giving up after
+    /// `max_attempts` collections, as in `Chain::generate_where`.
+    pub fn generate_where<P>(&self, predicate: P, max_attempts: usize) -> Result<Vec<T>, Exhausted>
+    where
+        P: FnMut(&[T]) -> bool,
+    {
+        self.generate_where_with_rng(predicate, max_attempts, &mut thread_rng())
+    }
+
+    /// Generates collections of tokens until one satisfies the given predicate, giving up after
+    /// `max_attempts` collections, using the given random number generator.
+    pub fn generate_where_with_rng<P, R>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<Vec<T>, Exhausted>
+    where
+        P: FnMut(&[T]) -> bool,
+        R: Rng + ?Sized,
+    {
+        Source::Frozen(self).generate_where(predicate, max_attempts, rng)
+    }
+
+    /// Produces an iterator over collections of tokens that satisfy the given predicate, as in
+    /// `Chain::iter_where`.
+    pub fn iter_where<P>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+    ) -> FilteredChainIterator<'_, T, P>
+    where
+        P: FnMut(&[T]) -> bool,
+    {
+        self.iter_where_with_rng(predicate, max_attempts, thread_rng())
+    }
+
+    /// Produces an iterator over collections of tokens that satisfy the given predicate, using the
+    /// given random number generator.
+    pub fn iter_where_with_rng<P, R>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+        rng: R,
+    ) -> FilteredChainIterator<'_, T, P, R>
+    where
+        P: FnMut(&[T]) -> bool,
+        R: Rng,
+    {
+        Source::Frozen(self).iter_where(predicate, max_attempts, rng)
+    }
+
+    /// Produces an iterator over distinct collections of tokens, as in `Chain::iter_distinct`.
+    pub fn iter_distinct(&self, max_attempts: usize) -> DistinctChainIterator<'_, T> {
+        self.iter_distinct_with_rng(max_attempts, thread_rng())
+    }
+
+    /// Produces an iterator over distinct collections of tokens that draws from the given random
+    /// number generator.
+    pub fn iter_distinct_with_rng<R: Rng>(
+        &self,
+        max_attempts: usize,
+        rng: R,
+    ) -> DistinctChainIterator<'_, T, R> {
+        Source::Frozen(self).iter_distinct(max_attempts, rng)
+    }
+}
+
+impl FrozenChain<String> {
+    /// Generates random strings of text until one satisfies the given predicate, giving up after
+    /// `max_attempts` strings.
+    pub fn generate_str_where<P>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+    ) -> Result<String, Exhausted>
+    where
+        P: FnMut(&str) -> bool,
+    {
+        self.generate_str_where_with_rng(predicate, max_attempts, &mut thread_rng())
+    }
+
+    /// Generates random strings of text until one satisfies the given predicate, giving up after
+    /// `max_attempts` strings, using the given random number generator.
+    pub fn generate_str_where_with_rng<P, R>(
+        &self,
+        predicate: P,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<String, Exhausted>
+    where
+        P: FnMut(&str) -> bool,
+        R: Rng + ?Sized,
+    {
+        Source::Frozen(self).generate_str_where(predicate, max_attempts, rng)
+    }
+
+    /// Produces an iterator over distinct generated strings, as in `iter_distinct`.
+    pub fn str_iter_distinct(&self, max_attempts: usize) -> DistinctChainStringIterator<'_> {
+        self.str_iter_distinct_with_rng(max_attempts, thread_rng())
+    }
+
+    /// Produces an iterator over distinct generated strings that draws from the given random
+    /// number generator.
+    pub fn str_iter_distinct_with_rng<R: Rng>(
+        &self,
+        max_attempts: usize,
+        rng: R,
+    ) -> DistinctChainStringIterator<'_, R> {
+        let vec_to_string: fn(Vec<String>) -> String = Chain::vec_to_string;
+        self.iter_distinct_with_rng(max_attempts, rng)
+            .map(vec_to_string)
+    }
+}
+
+/// An iterator over the collections of tokens of a Markov chain that satisfy a predicate.
+pub struct FilteredChainIterator<'a, T: Chainable + 'a, P, R: Rng = ThreadRng> {
+    chain: Source<'a, T>,
+    predicate: P,
+    max_attempts: usize,
+    rng: R,
+    exhausted: bool,
+}
+
+impl<'a, T, P, R> Iterator for FilteredChainIterator<'a, T, P, R>
+where
+    T: Chainable + 'a,
+    P: FnMut(&[T]) -> bool,
+    R: Rng,
+{
+    type Item = Result<Vec<T>, Exhausted>;
+    fn next(&mut self) -> Option<Result<Vec<T>, Exhausted>> {
+        if self.exhausted {
+            return None;
+        }
+        let next = self
+            .chain
+            .generate_where(&mut self.predicate, self.max_attempts, &mut self.rng);
+        self.exhausted = next.is_err();
+        Some(next)
+    }
+}
+
+/// An iterator over distinct strings of a Markov chain of strings.
+pub type DistinctChainStringIterator<'a, R = ThreadRng> =
+    Map<DistinctChainIterator<'a, String, R>, fn(Vec<String>) -> String>;
+
+/// An iterator over distinct collections of tokens of a Markov chain.
+pub struct DistinctChainIterator<'a, T: Chainable + 'a, R: Rng = ThreadRng> {
+    chain: Source<'a, T>,
+    seen: HashSet<Vec<T>>,
+    max_attempts: usize,
+    rng: R,
+}
+
+impl<'a, T, R> Iterator for DistinctChainIterator<'a, T, R>
+where
+    T: Chainable + 'a,
+    R: Rng,
+{
+    type Item = Vec<T>;
+    fn next(&mut self) -> Option<Vec<T>> {
+        for _ in 0..self.max_attempts {
+            let next = self.chain.generate_with_rng(&mut self.rng);
+            if !self.seen.contains(&next) {
+                self.seen.insert(next.clone());
+                return Some(next);
+            }
+        }
+        // Stop for good rather than retrying on the next call.
+        self.max_attempts = 0;
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Chain;
+    use super::Exhausted;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn generate_where() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![1u8, 2, 3])
+            .feed(vec![1, 4])
+            .feed(vec![2, 2]);
+        for _ in 0..20 {
+            let v = chain.generate_where(|v| v.contains(&4), 1000).unwrap();
+            assert_eq!(v[v.len() - 1], 4);
+        }
+        assert_eq!(
+            chain.generate_where(|v| v.contains(&5), 10),
+            Err(Exhausted { attempts: 10 })
+        );
+        assert_eq!(
+            Exhausted { attempts: 10 }.to_string(),
+            "no acceptable output in 10 attempts"
+        );
+        let frozen = chain.freeze().unwrap();
+        for seed in 0..10 {
+            assert_eq!(
+                frozen.generate_where_with_rng(
+                    |v| v.len() > 1,
+                    100,
+                    &mut StdRng::seed_from_u64(seed)
+                ),
+                chain.generate_where_with_rng(
+                    |v| v.len() > 1,
+                    100,
+                    &mut StdRng::seed_from_u64(seed)
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn iter_where() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![1u8, 2, 3])
+            .feed(vec![1, 4])
+            .feed(vec![2, 2]);
+        assert!(chain
+            .iter_where(|v| v.len() > 2, 1000)
+            .take(10)
+            .all(|v| v.unwrap().len() > 2));
+        let results: Vec<_> = chain.iter_where(|v| v.is_empty(), 5).collect();
+        assert_eq!(results, vec![Err(Exhausted { attempts: 5 })]);
+    }
+
+    #[test]
+    fn iter_distinct() {
+        let mut chain = Chain::new();
+        chain.feed(vec![1u8, 2]).feed(vec![1, 3]).feed(vec![4]);
+        let mut all: Vec<_> = chain.iter_distinct(1000).collect();
+        all.sort();
+        assert_eq!(all, vec![vec![1, 2], vec![1, 3], vec![4]]);
+        let mut frozen: Vec<_> = chain.freeze().unwrap().iter_distinct(1000).collect();
+        frozen.sort();
+        assert_eq!(frozen, all);
+        let mut chain = Chain::new();
+        chain.feed_str("I like cats").feed_str("you like dogs");
+        let strings: Vec<_> = chain.str_iter_distinct(1000).take(3).collect();
+        assert_eq!(strings.len(), 3);
+        assert!(strings
+            .iter()
+            .all(|s| strings.iter().filter(|t| t == &s).count() == 1));
+    }
+
+    #[test]
+    fn generate_str_where() {
+        let mut chain = Chain::new();
+        chain.feed_str("I like cats").feed_str("I hate dogs");
+        for _ in 0..10 {
+            let s = chain
+                .generate_str_where(|s| s.ends_with("dogs"), 1000)
+                .unwrap();
+            assert!(s.ends_with("dogs"));
+        }
+    }
+}
@@ -0,0 +1,892 @@
+//! A compact, read-only representation of a chain.
+
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::Result;
+use std::ops::Range;
+use std::path::Path;
+
+use rand::{thread_rng, Rng};
+
+use super::constrained::Edge;
+use super::ends::{self, Ends};
+use super::source::Source;
+use super::{
+    Backoff, Chain, Chainable, Id, InfiniteChainIterator, InfiniteChainStringIterator,
+    SizedChainIterator, SizedChainStringIterator, Smoothing, States, Token, Vocabulary, UNKNOWN,
+};
+
+/// Marks a successor whose context was never seen, which therefore ends any sequence.
+const UNSEEN: u32 = u32::MAX;
+
+/// The error returned when freezing a chain whose probabilities depend on more than its observed
+/// counts.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Unfreezable;
+
+impl fmt::Display for Unfreezable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a chain with smoothing or backoff cannot be frozen")
+    }
+}
+
+impl error::Error for Unfreezable {}
+
+/// A read-only Markov chain, created by `Chain::freeze`, that is much smaller and faster to
+/// sample than the chain it was built from.
+///
+/// Every token is replaced by an integer id, and the transitions of all contexts are packed into
+/// a few contiguous arrays, in the style of a compressed sparse row matrix. Each successor also
+/// records the context it leads to, so that generation never hashes a context. Given the same
+/// seed, a frozen chain generates the same sequences as the chain it was built from.
+///
+/// Only the observed counts and the sampling settings of the chain (temperature, `top_k` and
+/// `top_p`) are kept, so a chain with smoothing or backoff cannot be frozen. Otherwise, a frozen
+/// chain can generate and be queried in every way its chain can.
+///
+/// ```
+/// use markov::Chain;
+///
+/// let mut chain = Chain::new();
+/// chain.feed_str("I like cats").feed_str("I like dogs");
+/// let frozen = chain.freeze().unwrap();
+/// assert!(frozen.generate_str().starts_with("I like"));
+/// assert_eq!(frozen.thaw(), chain);
+/// ```
+#[derive(Clone, Debug)]
+pub struct FrozenChain<T: Chainable> {
+    /// An empty chain with the order, the settings and the overlap index of the frozen one.
+    settings: Chain<T>,
+    /// Every token, indexed by its id, in the order of the vocabulary of the chain.
+    tokens: Vec<Token<T>>,
+    /// The hash of every token along with its id, in order, so that tokens are looked up by
+    /// binary search rather than stored a second time as keys.
+    index: Vec<(u64, u32)>,
+    /// The id of `None`, which marks both the start and the end of a sequence, if it was ever fed.
+    end: Option<u32>,
+    /// The ids of the tokens of every context, `order` at a time, sorted by their last token
+    /// first so that the contexts that end the same way are next to each other.
+    contexts: Vec<u32>,
+    /// The range of the successors of every context, as offsets into the following arrays.
+    offsets: Vec<u32>,
+    successors: Vec<u32>,
+    /// The running totals of the counts of the successors of each context.
+    cumulative: Vec<u64>,
+    /// The context that every successor leads to, or `UNSEEN`.
+    targets: Vec<u32>,
+    /// The rows of the contexts from which the end of a sequence can never be reached, in order.
+    endless: Vec<u32>,
+}
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Creates a compact, read-only copy of the chain, for when it will not be fed again. This
+    /// returns an error if smoothing or backoff is enabled, as a frozen chain only keeps the
+    /// observed counts.
+    pub fn freeze(&self) -> std::result::Result<FrozenChain<T>, Unfreezable> {
+        if self.smoothing != Smoothing::None || self.backoff != Backoff::None {
+            return Err(Unfreezable);
+        }
+        // The ids of the chain are positions in its vocabulary, and so are those of the frozen
+        // chain, except that the end of sequence marker gets the position it has too.
+        let tokens: Vec<_> = self
+            .vocabulary
+            .iter()
+            .map(|(token, _)| token.clone())
+            .collect();
+        let mut index: Vec<_> = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (hash(token), id as u32))
+            .collect();
+        index.sort_unstable();
+        let end = self
+            .vocabulary
+            .position(&None)
+            .map(|position| position as u32);
+        let id = |token: &Token<Id>| token.or(end).unwrap_or(UNKNOWN);
+
+        let mut rows: Vec<_> = self
+            .map
+            .iter()
+            .filter(|&(_, states)| !states.is_empty())
+            .map(|(context, states)| (context.iter().map(id).collect::<Vec<_>>(), states))
+            .collect();
+        rows.sort_by(|a, b| a.0.iter().rev().cmp(b.0.iter().rev()));
+
+        let mut settings = self.settings();
+        settings.overlaps = self.overlaps.clone();
+        let mut frozen = FrozenChain {
+            settings,
+            end,
+            tokens,
+            index,
+            contexts: Vec::with_capacity(rows.len() * self.order),
+            offsets: vec![0],
+            successors: Vec::new(),
+            cumulative: Vec::new(),
+            targets: Vec::new(),
+            endless: Vec::new(),
+        };
+        for (context, _) in &rows {
+            frozen.contexts.extend_from_slice(context);
+        }
+        for (context, states) in rows {
+            let mut next = context[1..].to_vec();
+            next.push(0);
+            let mut sum = 0;
+            for &(token, count) in states.iter() {
+                next[self.order - 1] = id(&token);
+                let target = match token {
+                    Some(_) => frozen.find(&next).map_or(UNSEEN, |row| row as u32),
+                    None => UNSEEN,
+                };
+                sum += count as u64;
+                frozen.successors.push(next[self.order - 1]);
+                frozen.cumulative.push(sum);
+                frozen.targets.push(target);
+            }
+            frozen.offsets.push(frozen.successors.len() as u32);
+        }
+        let rows = (0..frozen.offsets.len() - 1).collect();
+        let mut endless: Vec<_> = ends::endless(rows, |&row| {
+            let range = frozen.range(row);
+            let mut following = Vec::new();
+            for i in frozen.support(&frozen.cumulative[range.clone()]) {
+                let i = range.start + i;
+                // A successor whose context was never seen ends the sequence too.
+                if Some(frozen.successors[i]) == frozen.end || frozen.targets[i] == UNSEEN {
+                    return None;
+                }
+                following.push(frozen.targets[i] as usize);
+            }
+            if following.is_empty() {
+                return None;
+            }
+            Some(following)
+        })
+        .into_iter()
+        .map(|row| row as u32)
+        .collect();
+        endless.sort_unstable();
+        frozen.endless = endless;
+        Ok(frozen)
+    }
+}
+
+impl<T> FrozenChain<T>
+where
+    T: Chainable,
+{
+    /// Gets the order of the chain, i.e. the number of previous tokens used for each mapping.
+    pub fn order(&self) -> usize {
+        self.settings.order
+    }
+
+    /// Determines whether or not the chain is empty. A chain is considered empty if nothing had
+    /// been fed into it.
+    pub fn is_empty(&self) -> bool {
+        self.successors.is_empty()
+    }
+
+    /// Creates a plain chain with the contents and settings of this one, which can be fed again.
+    pub fn thaw(&self) -> Chain<T> {
+        let token = |id: u32| self.tokens[id as usize].clone();
+        let rows = self.contexts.len() / self.order();
+        let contexts = (0..rows).map(|row| {
+            let context = self.context(row).iter().map(|&id| token(id)).collect();
+            let mut states = States::new();
+            let mut previous = 0;
+            for i in self.range(row) {
+                states.add(
+                    token(self.successors[i]),
+                    (self.cumulative[i] - previous) as usize,
+                );
+                previous = self.cumulative[i];
+            }
+            (context, states)
+        });
+        let mut vocabulary = Vocabulary::new();
+        for token in &self.tokens {
+            vocabulary.add(token.clone(), 0);
+        }
+        self.settings.assemble(contexts, &vocabulary)
+    }
+
+    /// Gets an empty chain with the order, the settings and the overlap index of this one.
+    pub(crate) fn settings(&self) -> &Chain<T> {
+        &self.settings
+    }
+
+    /// Gets the id of the given token, or `UNKNOWN` if it was never fed, as `Chain::id` does.
+    pub(crate) fn id(&self, token: &Token<T>) -> Token<Id> {
+        token.as_ref().map(|_| {
+            let hash = hash(token);
+            let start = self.index.partition_point(|&(other, _)| other < hash);
+            self.index[start..]
+                .iter()
+                .take_while(|&&(other, _)| other == hash)
+                .map(|&(_, id)| id)
+                .find(|&id| self.tokens[id as usize] == *token)
+                .unwrap_or(UNKNOWN)
+        })
+    }
+
+    /// Gets the ids of the given tokens.
+    fn ids(&self, tokens: &[Token<T>]) -> Vec<Token<Id>> {
+        tokens.iter().map(|token| self.id(token)).collect()
+    }
+
+    /// Gets the token with the given id.
+    pub(crate) fn token(&self, id: Id) -> &T {
+        self.tokens[id as usize].as_ref().unwrap()
+    }
+
+    /// Replaces `None` in the given ids with the id of the end of a sequence. If it was never fed,
+    /// `UNKNOWN` takes its place, which no context contains either.
+    fn unwrap_ids(&self, ids: &[Token<Id>]) -> Vec<u32> {
+        ids.iter()
+            .map(|id| id.or(self.end).unwrap_or(UNKNOWN))
+            .collect()
+    }
+
+    /// Replaces the id of the end of a sequence with `None`.
+    fn wrap_id(&self, id: u32) -> Token<Id> {
+        if Some(id) == self.end {
+            None
+        } else {
+            Some(id)
+        }
+    }
+
+    /// Gets the ids of the tokens of the context of the given row.
+    fn context(&self, row: usize) -> &[u32] {
+        let order = self.order();
+        &self.contexts[row * order..(row + 1) * order]
+    }
+
+    /// Gets the range of the successors of the context of the given row.
+    fn range(&self, row: usize) -> Range<usize> {
+        self.offsets[row] as usize..self.offsets[row + 1] as usize
+    }
+
+    /// Compares the end of the context of the given row with the given suffix, last token first.
+    fn compare(&self, row: usize, suffix: &[u32]) -> Ordering {
+        let context = self.context(row);
+        context[context.len() - suffix.len()..]
+            .iter()
+            .rev()
+            .cmp(suffix.iter().rev())
+    }
+
+    /// Gets the rows of the contexts that end with the given tokens.
+    fn rows_ending_with(&self, suffix: &[u32]) -> Range<usize> {
+        let rows = self.contexts.len() / self.order();
+        let (mut start, mut end) = (0, rows);
+        // Contexts are sorted by their last token first, so these rows are contiguous.
+        while start < end {
+            let mid = (start + end) / 2;
+            if self.compare(mid, suffix) == Ordering::Less {
+                start = mid + 1;
+            } else {
+                end = mid;
+            }
+        }
+        end = rows;
+        let mut low = start;
+        while low < end {
+            let mid = (low + end) / 2;
+            if self.compare(mid, suffix) == Ordering::Greater {
+                end = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        start..end
+    }
+
+    /// Gets the row of the given context, if it was seen.
+    fn find(&self, context: &[u32]) -> Option<usize> {
+        let rows = self.rows_ending_with(context);
+        if rows.is_empty() {
+            None
+        } else {
+            Some(rows.start)
+        }
+    }
+
+    /// Gets the row of the context with the given ids, if it was seen.
+    pub(crate) fn row(&self, context: &[Token<Id>]) -> Option<usize> {
+        self.find(&self.unwrap_ids(context))
+    }
+
+    /// Determines whether the end of a sequence can never be reached from the given row.
+    fn is_endless(&self, row: usize) -> bool {
+        self.endless.binary_search(&(row as u32)).is_ok()
+    }
+
+    /// Gets the running totals of the counts of some successors back as their counts.
+    fn weights(&self, cumulative: &[u64]) -> Vec<f64> {
+        let mut previous = 0;
+        cumulative
+            .iter()
+            .map(|&sum| {
+                let weight = (sum - previous) as f64;
+                previous = sum;
+                weight
+            })
+            .collect()
+    }
+
+    /// Gets the indices of the successors with the given running totals that may be sampled.
+    fn support(&self, cumulative: &[u64]) -> Vec<usize> {
+        self.settings.sampling.support(self.weights(cumulative))
+    }
+
+    /// Samples the index of a successor given their running totals, in the same way as the chain
+    /// it was built from. This returns `None` if there is none.
+    fn sample<R: Rng + ?Sized>(&self, cumulative: &[u64], rng: &mut R) -> Option<usize> {
+        let total = *cumulative.last()?;
+        if self.settings.sampling.is_plain() {
+            let cap = rng.gen_range(0..total);
+            return Some(cumulative.partition_point(|&sum| sum <= cap));
+        }
+        self.settings.sampling.sample(self.weights(cumulative), rng)
+    }
+
+    /// Gets the successors of every context that ends with the given tokens, and the running
+    /// totals of their combined counts, in the order of their ids.
+    fn successors_ending_with(&self, suffix: &[u32]) -> (Vec<u32>, Vec<u64>) {
+        let mut counts = BTreeMap::new();
+        for row in self.rows_ending_with(suffix) {
+            let mut previous = 0;
+            for i in self.range(row) {
+                *counts.entry(self.successors[i]).or_insert(0) += self.cumulative[i] - previous;
+                previous = self.cumulative[i];
+            }
+        }
+        let mut sum = 0;
+        counts
+            .into_iter()
+            .map(|(id, count)| {
+                sum += count;
+                (id, sum)
+            })
+            .unzip()
+    }
+
+    /// Gets the successors of the given context, which may be shorter than the order of the
+    /// chain, and the running totals of their counts.
+    fn successors_of(&self, context: &[Token<Id>]) -> (Vec<u32>, Vec<u64>) {
+        let context = self.unwrap_ids(context);
+        if context.len() < self.order() {
+            return self.successors_ending_with(&context);
+        }
+        match self.find(&context) {
+            Some(row) => {
+                let range = self.range(row);
+                (
+                    self.successors[range.clone()].to_vec(),
+                    self.cumulative[range].to_vec(),
+                )
+            }
+            None => (Vec::new(), Vec::new()),
+        }
+    }
+
+    /// Samples the token following the given context, as `Chain::next_token` does.
+    pub(crate) fn next_token<R: Rng + ?Sized>(
+        &self,
+        context: &[Token<Id>],
+        rng: &mut R,
+    ) -> Token<Id> {
+        let (ids, cumulative) = self.successors_of(context);
+        let i = self.sample(&cumulative, rng)?;
+        self.wrap_id(ids[i])
+    }
+
+    /// Samples the token following the given context, with the weight of each successor
+    /// multiplied by the given function, as `Chain::next_token_weighted` does.
+    pub(crate) fn next_token_weighted<R, F>(
+        &self,
+        context: &[Token<Id>],
+        mut weight: F,
+        rng: &mut R,
+    ) -> Option<Token<Id>>
+    where
+        R: Rng + ?Sized,
+        F: FnMut(&Token<Id>) -> f64,
+    {
+        let (ids, cumulative) = self.successors_of(context);
+        let weights = ids
+            .iter()
+            .zip(self.weights(&cumulative))
+            .map(|(&id, count)| count * weight(&self.wrap_id(id)))
+            .collect();
+        let i = self.settings.sampling.sample(weights, rng)?;
+        Some(self.wrap_id(ids[i]))
+    }
+
+    /// Determines whether the end of a sequence can be reached from the given context, as
+    /// `Chain::can_end` does.
+    pub(crate) fn can_end(&self, context: &[Token<Id>], ends: &mut Ends<Vec<Token<Id>>>) -> bool {
+        if context.len() == self.order() {
+            // Full contexts are worked out when freezing, and lead to full contexts only.
+            return self.row(context).map_or(true, |row| !self.is_endless(row));
+        }
+        ends.reachable(&context.to_vec(), |context| self.following(context))
+    }
+
+    /// Gets the contexts that may follow the given one when sampling, or `None` if the sequence
+    /// may end right after it.
+    fn following(&self, context: &[Token<Id>]) -> Option<Vec<Vec<Token<Id>>>> {
+        if context.len() == self.order() {
+            return match self.row(context) {
+                Some(row) if self.is_endless(row) => Some(Vec::new()),
+                _ => None,
+            };
+        }
+        let (ids, cumulative) = self.successors_of(context);
+        let support = self.support(&cumulative);
+        // A context without successors ends the sequence.
+        if support.is_empty() {
+            return None;
+        }
+        support
+            .into_iter()
+            .map(|i| {
+                let mut following = context.to_vec();
+                following.push(Some(self.wrap_id(ids[i])?));
+                Some(following)
+            })
+            .collect()
+    }
+
+    /// Gets the transitions out of every row, along with their probabilities and the rows they
+    /// lead to. Transitions into contexts that were never seen are left out.
+    pub(crate) fn edges(&self) -> Vec<Vec<Edge<'_, T>>> {
+        (0..self.offsets.len() - 1)
+            .map(|row| {
+                let range = self.range(row);
+                let total = self.cumulative[range.end - 1] as f64;
+                let weights = self.weights(&self.cumulative[range.clone()]);
+                range
+                    .zip(weights)
+                    .filter_map(|(i, weight)| match self.wrap_id(self.successors[i]) {
+                        None => Some((None, weight / total, None)),
+                        Some(_) if self.targets[i] == UNSEEN => None,
+                        Some(id) => Some((
+                            Some(self.token(id)),
+                            weight / total,
+                            Some(self.targets[i] as usize),
+                        )),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Walks the chain from the given context until the end of a sequence is reached, or can no
+    /// longer be reached, appending every generated token to `ret`. The context may be shorter
+    /// than the order of the chain, in which case it grows with every generated token until it
+    /// is long enough.
+    fn generate_from_context<R: Rng + ?Sized>(
+        &self,
+        mut context: Vec<Token<Id>>,
+        mut ret: Vec<T>,
+        rng: &mut R,
+    ) -> Vec<T> {
+        let mut ends = Ends::new();
+        while context.len() < self.order() {
+            // Stop rather than loop forever once the end of a sequence is out of reach.
+            if !self.can_end(&context, &mut ends) {
+                return ret;
+            }
+            match self.next_token(&context, rng) {
+                Some(next) => {
+                    context.push(Some(next));
+                    ret.push(self.token(next).clone());
+                }
+                None => return ret,
+            }
+        }
+        let mut row = self.row(&context);
+        while let Some(current) = row {
+            if self.is_endless(current) {
+                break;
+            }
+            let range = self.range(current);
+            let i = match self.sample(&self.cumulative[range.clone()], rng) {
+                Some(i) => range.start + i,
+                None => break,
+            };
+            match self.wrap_id(self.successors[i]) {
+                Some(next) => ret.push(self.token(next).clone()),
+                None => break,
+            }
+            row = Some(self.targets[i])
+                .filter(|&target| target != UNSEEN)
+                .map(|target| target as usize);
+        }
+        ret
+    }
+
+    /// Generates a collection of tokens from the chain, as in `Chain::generate`.
+    pub fn generate(&self) -> Vec<T> {
+        self.generate_with_rng(&mut thread_rng())
+    }
+
+    /// Generates a collection of tokens from the chain using the given random number generator.
+    pub fn generate_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<T> {
+        self.generate_from_context(vec![None; self.order()], Vec::new(), rng)
+    }
+
+    /// Generates a collection of tokens from the chain, starting with the given token, as in
+    /// `Chain::generate_from_token`. This returns an empty vector if the token is not found.
+    pub fn generate_from_token(&self, token: T) -> Vec<T> {
+        self.generate_from_token_with_rng(token, &mut thread_rng())
+    }
+
+    /// Generates a collection of tokens from the chain, starting with the given token and using
+    /// the given random number generator. This returns an empty vector if the token is not found.
+    pub fn generate_from_token_with_rng<R: Rng + ?Sized>(&self, token: T, rng: &mut R) -> Vec<T> {
+        let token = Some(token);
+        let mut context = vec![None; self.order() - 1];
+        context.push(self.id(&token));
+        if self.row(&context).is_none() {
+            return Vec::new();
+        }
+        self.generate_from_context(context, token.into_iter().collect(), rng)
+    }
+
+    /// Generates a collection of tokens from the chain that continues the given prefix from
+    /// anywhere within a sequence, as in `Chain::generate_from_prefix`. This returns `None` if the
+    /// context was never seen.
+    pub fn generate_from_prefix(&self, prefix: &[T]) -> Option<Vec<T>> {
+        self.generate_from_prefix_with_rng(prefix, &mut thread_rng())
+    }
+
+    /// Generates a collection of tokens from the chain that continues the given prefix, using the
+    /// given random number generator. This returns `None` if the context was never seen.
+    pub fn generate_from_prefix_with_rng<R: Rng + ?Sized>(
+        &self,
+        prefix: &[T],
+        rng: &mut R,
+    ) -> Option<Vec<T>> {
+        if prefix.is_empty() {
+            return Some(self.generate_with_rng(rng));
+        }
+        let start = prefix.len().saturating_sub(self.order());
+        let context: Vec<_> = prefix[start..].iter().cloned().map(Some).collect();
+        let context = self.ids(&context);
+        if self.successors_of(&context).0.is_empty() {
+            return None;
+        }
+        Some(self.generate_from_context(context, prefix.to_vec(), rng))
+    }
+
+    /// Gets every possible successor of the given context along with its probability, as in
+    /// `Chain::successors`.
+    pub fn successors(&self, context: &[Option<T>]) -> Vec<(Option<&T>, f64)> {
+        assert_eq!(context.len(), self.order());
+        let row = match self.row(&self.ids(context)) {
+            Some(row) => row,
+            None => return Vec::new(),
+        };
+        let range = self.range(row);
+        let total = self.cumulative[range.end - 1] as f64;
+        let mut previous = 0;
+        range
+            .map(|i| {
+                let count = self.cumulative[i] - previous;
+                previous = self.cumulative[i];
+                let token = self.tokens[self.successors[i] as usize].as_ref();
+                (token, count as f64 / total)
+            })
+            .collect()
+    }
+
+    /// Gets the probability that the given token follows the given context, as in
+    /// `Chain::probability`.
+    pub fn probability(&self, context: &[Option<T>], next: Option<&T>) -> f64 {
+        self.successors(context)
+            .into_iter()
+            .find(|&(token, _)| token == next)
+            .map_or(0.0, |(_, p)| p)
+    }
+
+    /// Computes the natural logarithm of the probability that the chain generates the given
+    /// collection of tokens, as in `Chain::log_likelihood`.
+    pub fn log_likelihood<S: AsRef<[T]>>(&self, tokens: S) -> f64 {
+        let order = self.order();
+        let toks = self.settings.pad(tokens.as_ref());
+        toks.windows(order + 1)
+            .map(|p| self.probability(&p[0..order], p[order].as_ref()).ln())
+            .sum()
+    }
+
+    /// Computes the cross-entropy of the chain on the given collections of tokens, in bits per
+    /// transition, as in `Chain::cross_entropy`.
+    pub fn cross_entropy<S: AsRef<[T]>>(&self, sequences: &[S]) -> f64 {
+        Source::Frozen(self).cross_entropy(sequences)
+    }
+
+    /// Computes the perplexity of the chain on the given collections of tokens, as in
+    /// `Chain::perplexity`.
+    pub fn perplexity<S: AsRef<[T]>>(&self, sequences: &[S]) -> f64 {
+        self.cross_entropy(sequences).exp2()
+    }
+
+    /// Produces an infinite iterator of generated token collections, as in `Chain::iter`.
+    pub fn iter(&self) -> InfiniteChainIterator<'_, T> {
+        self.iter_with_rng(thread_rng())
+    }
+
+    /// Produces an infinite iterator of generated token collections that draws from the given
+    /// random number generator.
+    pub fn iter_with_rng<R: Rng>(&self, rng: R) -> InfiniteChainIterator<'_, T, R> {
+        InfiniteChainIterator {
+            chain: Source::Frozen(self),
+            rng,
+        }
+    }
+
+    /// Produces an iterator for the specified number of generated token collections.
+    pub fn iter_for(&self, size: usize) -> SizedChainIterator<'_, T> {
+        self.iter_for_with_rng(size, thread_rng())
+    }
+
+    /// Produces an iterator for the specified number of generated token collections that draws
+    /// from the given random number generator.
+    pub fn iter_for_with_rng<R: Rng>(&self, size: usize, rng: R) -> SizedChainIterator<'_, T, R> {
+        SizedChainIterator {
+            chain: Source::Frozen(self),
+            size,
+            rng,
+        }
+    }
+}
+
+impl FrozenChain<String> {
+    /// Generates a random string of text.
+    pub fn generate_str(&self) -> String {
+        Chain::vec_to_string(self.generate())
+    }
+
+    /// Generates a random string of text using the given random number generator.
+    pub fn generate_str_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> String {
+        Chain::vec_to_string(self.generate_with_rng(rng))
+    }
+
+    /// Generates a random string of text starting with the desired token. This returns an empty
+    /// string if the token is not found.
+    pub fn generate_str_from_token(&self, string: &str) -> String {
+        Chain::vec_to_string(self.generate_from_token(string.to_owned()))
+    }
+
+    /// Generates a random string of text starting with the desired token, using the given random
+    /// number generator. This returns an empty string if the token is not found.
+    pub fn generate_str_from_token_with_rng<R: Rng + ?Sized>(
+        &self,
+        string: &str,
+        rng: &mut R,
+    ) -> String {
+        Chain::vec_to_string(self.generate_from_token_with_rng(string.to_owned(), rng))
+    }
+
+    /// Generates a random string of text that continues the given prefix, as in
+    /// `Chain::generate_str_from_prefix`. This returns `None` if the context was never seen.
+    pub fn generate_str_from_prefix(&self, prefix: &str) -> Option<String> {
+        self.generate_str_from_prefix_with_rng(prefix, &mut thread_rng())
+    }
+
+    /// Generates a random string of text that continues the given prefix, using the given random
+    /// number generator. This returns `None` if the context was never seen.
+    pub fn generate_str_from_prefix_with_rng<R: Rng + ?Sized>(
+        &self,
+        prefix: &str,
+        rng: &mut R,
+    ) -> Option<String> {
+        let prefix = prefix
+            .split_whitespace()
+            .map(|s| s.to_owned())
+            .collect::<Vec<_>>();
+        self.generate_from_prefix_with_rng(&prefix, rng)
+            .map(Chain::vec_to_string)
+    }
+
+    /// Computes the natural logarithm of the probability that the chain generates the given string
+    /// of text, split on spaces as in `Chain::feed_str`.
+    pub fn log_likelihood_str(&self, string: &str) -> f64 {
+        self.log_likelihood(string.split(' ').map(|s| s.to_owned()).collect::<Vec<_>>())
+    }
+
+    /// Computes the cross-entropy of the chain, in bits per transition, on a held-out file, as in
+    /// `Chain::cross_entropy_file`.
+    pub fn cross_entropy_file<P: AsRef<Path>>(&self, path: P) -> Result<f64> {
+        Source::Frozen(self).cross_entropy_file(path)
+    }
+
+    /// Computes the perplexity of the chain on a held-out file, as in `Chain::perplexity_file`.
+    pub fn perplexity_file<P: AsRef<Path>>(&self, path: P) -> Result<f64> {
+        self.cross_entropy_file(path).map(f64::exp2)
+    }
+
+    /// Produces an infinite iterator of generated strings.
+    pub fn str_iter(&self) -> InfiniteChainStringIterator<'_> {
+        self.str_iter_with_rng(thread_rng())
+    }
+
+    /// Produces an infinite iterator of generated strings that draws from the given random number
+    /// generator.
+    pub fn str_iter_with_rng<R: Rng>(&self, rng: R) -> InfiniteChainStringIterator<'_, R> {
+        let vec_to_string: fn(Vec<String>) -> String = Chain::vec_to_string;
+        self.iter_with_rng(rng).map(vec_to_string)
+    }
+
+    /// Produces a sized iterator of generated strings.
+    pub fn str_iter_for(&self, size: usize) -> SizedChainStringIterator<'_> {
+        self.str_iter_for_with_rng(size, thread_rng())
+    }
+
+    /// Produces a sized iterator of generated strings that draws from the given random number
+    /// generator.
+    pub fn str_iter_for_with_rng<R: Rng>(
+        &self,
+        size: usize,
+        rng: R,
+    ) -> SizedChainStringIterator<'_, R> {
+        let vec_to_string: fn(Vec<String>) -> String = Chain::vec_to_string;
+        self.iter_for_with_rng(size, rng).map(vec_to_string)
+    }
+}
+
+/// Hashes a token, to look it up in the index of a frozen chain.
+fn hash<T: Hash>(token: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    token.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Backoff, Chain, Smoothing};
+    use super::Unfreezable;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn same_as_chain() {
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_str("I like cats and I like dogs")
+            .feed_str("you like cats and you hate birds")
+            .feed_str("I hate cats");
+        let frozen = chain.freeze().unwrap();
+        assert_eq!(frozen.thaw(), chain);
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut frozen_rng = StdRng::seed_from_u64(seed);
+            assert_eq!(
+                chain.generate_str_with_rng(&mut rng),
+                frozen.generate_str_with_rng(&mut frozen_rng)
+            );
+            let prefix = ["cats".to_owned()];
+            assert_eq!(
+                chain.generate_from_prefix_with_rng(&prefix, &mut rng),
+                frozen.generate_from_prefix_with_rng(&prefix, &mut frozen_rng)
+            );
+            assert_eq!(
+                chain.generate_str_from_token_with_rng("you", &mut rng),
+                frozen.generate_str_from_token_with_rng("you", &mut frozen_rng)
+            );
+        }
+        let seeded = |seed| StdRng::seed_from_u64(seed);
+        assert!(chain
+            .str_iter_for_with_rng(20, seeded(1))
+            .eq(frozen.str_iter_for_with_rng(20, seeded(1))));
+        assert!(chain
+            .iter_with_rng(seeded(2))
+            .take(20)
+            .eq(frozen.iter_with_rng(seeded(2)).take(20)));
+        chain.set_temperature(2.0).set_top_k(Some(2));
+        let frozen = chain.freeze().unwrap();
+        for seed in 0..20 {
+            assert_eq!(
+                chain.generate_with_rng(&mut StdRng::seed_from_u64(seed)),
+                frozen.generate_with_rng(&mut StdRng::seed_from_u64(seed))
+            );
+        }
+        assert_eq!(frozen.thaw().temperature(), 2.0);
+    }
+
+    #[test]
+    fn queries() {
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_str("I like cats and I like dogs")
+            .feed_str("you like cats and you hate birds")
+            .feed_str("I hate cats");
+        let frozen = chain.freeze().unwrap();
+        let like = [Some("I".to_owned()), Some("like".to_owned())];
+        assert_eq!(frozen.successors(&like), chain.successors(&like));
+        assert_eq!(
+            frozen.probability(&like, Some(&"dogs".to_owned())),
+            chain.probability(&like, Some(&"dogs".to_owned()))
+        );
+        for s in &["I like cats and you hate birds", "you hate cats", "I"] {
+            assert_eq!(frozen.log_likelihood_str(s), chain.log_likelihood_str(s));
+        }
+        let held_out = [vec!["I".to_owned(), "hate".to_owned(), "cats".to_owned()]];
+        assert_eq!(frozen.perplexity(&held_out), chain.perplexity(&held_out));
+        assert_eq!(
+            frozen.generate_str_from_token("you").split(' ').next(),
+            Some("you")
+        );
+        assert_eq!(frozen.generate_str_from_token("cats"), "");
+        assert_eq!(frozen.generate_str_from_prefix("zebras"), None);
+        assert!(!frozen.is_empty());
+        assert!(Chain::<u8>::new().freeze().unwrap().is_empty());
+        assert_eq!(
+            frozen.clone().perplexity_file("test").unwrap(),
+            chain.perplexity_file("test").unwrap()
+        );
+        assert!(format!("{:?}", frozen).contains("\"hate\""));
+    }
+
+    #[test]
+    fn stream_without_end() {
+        let mut chain = Chain::new();
+        chain.feed_stream(vec![1u8, 2, 1, 3]);
+        let frozen = chain.freeze().unwrap();
+        assert_eq!(frozen.successors(&[Some(9)]), Vec::new());
+        assert_eq!(frozen.generate_from_token(9), Vec::<u8>::new());
+        for seed in 0..10 {
+            assert_eq!(
+                frozen.generate_from_token_with_rng(1, &mut StdRng::seed_from_u64(seed)),
+                chain.generate_from_token_with_rng(1, &mut StdRng::seed_from_u64(seed))
+            );
+        }
+    }
+
+    #[test]
+    fn freeze_smoothed() {
+        let mut chain = Chain::new();
+        chain.feed_str("I like cats");
+        chain.set_smoothing(Smoothing::Additive(1.0));
+        assert_eq!(chain.freeze().err(), Some(Unfreezable));
+        chain.set_smoothing(Smoothing::None);
+        chain.set_backoff(Backoff::Stupid(0.4));
+        assert_eq!(chain.freeze().err(), Some(Unfreezable));
+        assert_eq!(
+            Unfreezable.to_string(),
+            "a chain with smoothing or backoff cannot be frozen"
+        );
+    }
+}
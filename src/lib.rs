@@ -18,6 +18,26 @@
 //! chain.feed(vec![1u8, 2, 3, 5]).feed([3u8, 9, 2]);
 //! println!("{:?}", chain.generate());
 //! ```
+//!
+//! Every generating method has a `_with_rng` counterpart that takes a caller-supplied random
+//! number generator, which makes output reproducible when the generator is seeded.
+//!
+//! ```
+//! extern crate markov;
+//! extern crate rand;
+//!
+//! use markov::Chain;
+//! use rand::rngs::StdRng;
+//! use rand::SeedableRng;
+//!
+//! # fn main() {
+//! let mut chain = Chain::new();
+//! chain.feed_str("I like cats and I like dogs.");
+//! let first = chain.generate_str_with_rng(&mut StdRng::seed_from_u64(42));
+//! let second = chain.generate_str_with_rng(&mut StdRng::seed_from_u64(42));
+//! assert_eq!(first, second);
+//! # }
+//! ```
 #![warn(missing_docs)]
 
 #[cfg(feature = "graph")]
@@ -25,6 +45,8 @@ extern crate itertools;
 #[cfg(feature = "graph")]
 extern crate petgraph;
 extern crate rand;
+#[cfg(feature = "rayon")]
+extern crate rayon;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -32,44 +54,147 @@ extern crate serde_derive;
 extern crate serde_yaml;
 
 use std::borrow::ToOwned;
-use std::collections::hash_map::Entry::{Occupied, Vacant};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
 use std::fs::File;
 use std::hash::Hash;
 use std::io::prelude::*;
 use std::io::{BufReader, Result};
 #[cfg(feature = "yaml")]
 use std::io::{Error, ErrorKind};
-use std::iter::Map;
+use std::iter::{FromIterator, Map};
+use std::marker::PhantomData;
 use std::path::Path;
+use std::slice;
+use std::sync::OnceLock;
 
 #[cfg(feature = "graph")]
 use itertools::Itertools;
 #[cfg(feature = "graph")]
 use petgraph::graph::Graph;
+use rand::rngs::ThreadRng;
 use rand::{thread_rng, Rng};
 #[cfg(feature = "yaml")]
 use serde::de::DeserializeOwned;
-#[cfg(feature = "yaml")]
-use serde::Serialize;
+use serde::de::{Deserialize, Deserializer, Error as DeError, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeStruct, Serializer};
 #[cfg(feature = "yaml")]
 use serde_yaml as yaml;
 
+mod concurrent;
+mod constrained;
+mod decode;
+mod ends;
+mod filter;
+mod frozen;
+mod limits;
+mod originality;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod sampling;
+mod smoothing;
+mod source;
+mod stream;
+mod vocabulary;
+mod walk;
+
+pub use concurrent::ConcurrentChain;
+pub use constrained::{Automaton, Constrained, Dfa, ExactLength};
+use ends::Ends;
+pub use filter::{
+    DistinctChainIterator, DistinctChainStringIterator, Exhausted, FilteredChainIterator,
+};
+pub use frozen::{FrozenChain, Unfreezable};
+pub use limits::{LimitPolicy, Limits};
+pub use originality::Originality;
+use originality::OverlapIndex;
+use sampling::Sampling;
+use smoothing::Levels;
+pub use smoothing::{Backoff, Smoothing};
+use source::Source;
+use vocabulary::Vocabulary;
+pub use walk::{TerminalPolicy, Walker};
+
 /// The definition of all types that can be used in a `Chain`.
 pub trait Chainable: Eq + Hash + Clone {}
 impl<T> Chainable for T where T: Eq + Hash + Clone {}
 
 type Token<T> = Option<T>;
 
+/// The id of a token of a chain, which is its position in the vocabulary of the chain. Chains
+/// count their transitions by id, so that feeding them only clones tokens they have never seen.
+type Id = u32;
+
+/// The id of any token that was never fed into a chain, which follows no context. No token that
+/// was fed is ever given this id.
+const UNKNOWN: Id = Id::MAX;
+
+/// Gets the id of the token at the given position in the vocabulary of a chain.
+///
+/// # Panics
+///
+/// Panics if the position does not fit in an id other than `UNKNOWN`.
+fn to_id(position: usize) -> Id {
+    Id::try_from(position)
+        .ok()
+        .filter(|&id| id != UNKNOWN)
+        .expect("a chain holds too many distinct tokens to give each an id")
+}
+
 /// A generic [Markov chain](https://en.wikipedia.org/wiki/Markov_chain) for almost any type.
 /// In particular, elements of the chain must be `Eq`, `Hash`, and `Clone`.
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+///
+/// The possible successors of every state are kept in the order they were first fed into the
+/// chain. Generation therefore never depends on hashing, and a chain fed the same corpus will
+/// produce byte-identical output for a given seed on every platform and in every process, as long
+/// as the same version of this crate (and of the random number generator) is used.
+///
+/// Every distinct token is stored once, and transitions are counted by small integer ids instead,
+/// so a chain takes little more memory than its vocabulary and its counts.
+#[derive(Clone)]
 pub struct Chain<T>
 where
     T: Chainable,
 {
-    map: HashMap<Vec<Token<T>>, HashMap<Token<T>, usize>>,
+    /// The successors of every context, with every token replaced by its id.
+    map: HashMap<Vec<Token<Id>>, States<Id>>,
+    order: usize,
+    /// Every token in the order it was first fed, with the number of times it followed a context.
+    vocabulary: Vocabulary<T>,
+    smoothing: Smoothing,
+    smoothed_sampling: bool,
+    backoff: Backoff,
+    sampling: Sampling,
+    overlaps: Option<OverlapIndex>,
+    stream_tail: Vec<T>,
+    levels: Cache<Levels>,
+    /// The seen contexts from which the end of a sequence can never be reached.
+    endless: Cache<HashSet<Vec<Token<Id>>>>,
+}
+
+/// The saved form of a chain, which spells out every token rather than its id.
+#[derive(Deserialize)]
+struct Saved<T>
+where
+    T: Chainable,
+{
+    map: HashMap<Vec<Token<T>>, States<T>>,
     order: usize,
+    #[serde(default = "Vocabulary::new")]
+    vocabulary: Vocabulary<T>,
+    #[serde(default)]
+    smoothing: Smoothing,
+    #[serde(default)]
+    smoothed_sampling: bool,
+    #[serde(default)]
+    backoff: Backoff,
+    #[serde(default)]
+    sampling: Sampling,
+    #[serde(default)]
+    overlaps: Option<OverlapIndex>,
+    #[serde(default = "Vec::new")]
+    stream_tail: Vec<T>,
 }
 
 impl<T> Default for Chain<T>
@@ -81,6 +206,169 @@ where
     }
 }
 
+impl<T> PartialEq for Chain<T>
+where
+    T: Chainable,
+{
+    /// Compares the tokens of both chains rather than their ids, which depend on the order tokens
+    /// were first fed in.
+    fn eq(&self, other: &Chain<T>) -> bool {
+        let same_successors = |states: &States<Id>, others: &States<Id>| {
+            states.len() == others.len()
+                && states.iter().all(|(id, count)| {
+                    others.count(&other.id(&self.resolve(id).cloned())) == *count
+                })
+        };
+        self.order == other.order
+            && self.vocabulary == other.vocabulary
+            && self.smoothing == other.smoothing
+            && self.smoothed_sampling == other.smoothed_sampling
+            && self.backoff == other.backoff
+            && self.sampling == other.sampling
+            && self.overlaps == other.overlaps
+            && self.stream_tail == other.stream_tail
+            && self.map.len() == other.map.len()
+            && self.map.iter().all(|(context, states)| {
+                let context = other.ids(&self.resolve_all(context));
+                other
+                    .map
+                    .get(&context)
+                    .is_some_and(|others| same_successors(states, others))
+            })
+    }
+}
+
+impl<T> Serialize for Chain<T>
+where
+    T: Chainable + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut chain = serializer.serialize_struct("Chain", 9)?;
+        chain.serialize_field("map", &Spelled(self, &self.map))?;
+        chain.serialize_field("order", &self.order)?;
+        chain.serialize_field("vocabulary", &self.vocabulary)?;
+        chain.serialize_field("smoothing", &self.smoothing)?;
+        chain.serialize_field("smoothed_sampling", &self.smoothed_sampling)?;
+        chain.serialize_field("backoff", &self.backoff)?;
+        chain.serialize_field("sampling", &self.sampling)?;
+        chain.serialize_field("overlaps", &self.overlaps)?;
+        chain.serialize_field("stream_tail", &self.stream_tail)?;
+        chain.end()
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Chain<T>
+where
+    T: Chainable + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let saved = Saved::deserialize(deserializer)?;
+        if saved.order == 0 {
+            return Err(D::Error::custom("the order of a chain must be positive"));
+        }
+        if !saved.smoothing.is_valid() {
+            return Err(D::Error::custom(
+                "the parameter of the smoothing is out of range",
+            ));
+        }
+        if !saved.backoff.is_valid() {
+            return Err(D::Error::custom(
+                "the parameter of the backoff is out of range",
+            ));
+        }
+        if !saved.sampling.is_valid() {
+            return Err(D::Error::custom("the sampling settings are out of range"));
+        }
+        let mut chain = Chain::of_order(saved.order);
+        chain.smoothing = saved.smoothing;
+        chain.smoothed_sampling = saved.smoothed_sampling;
+        chain.backoff = saved.backoff;
+        chain.sampling = saved.sampling;
+        chain.overlaps = saved.overlaps;
+        chain.stream_tail = saved.stream_tail;
+        Ok(chain.assemble(saved.map, &saved.vocabulary))
+    }
+}
+
+/// A part of a chain that is serialized or formatted with every id replaced by its token.
+struct Spelled<'a, T: Chainable + 'a, V: 'a>(&'a Chain<T>, &'a V);
+
+impl<'a, T> Serialize for Spelled<'a, T, HashMap<Vec<Token<Id>>, States<Id>>>
+where
+    T: Chainable + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let Spelled(chain, contexts) = *self;
+        let mut map = serializer.serialize_map(Some(contexts.len()))?;
+        for (context, states) in contexts {
+            let context: Vec<_> = context.iter().map(|id| chain.resolve(id)).collect();
+            map.serialize_entry(&context, &Spelled(chain, states))?;
+        }
+        map.end()
+    }
+}
+
+impl<'a, T> Serialize for Spelled<'a, T, States<Id>>
+where
+    T: Chainable + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let Spelled(chain, states) = *self;
+        let mut map = serializer.serialize_map(Some(states.len()))?;
+        for (id, count) in states.iter() {
+            map.serialize_entry(&chain.resolve(id), count)?;
+        }
+        map.end()
+    }
+}
+
+impl<T> fmt::Debug for Chain<T>
+where
+    T: Chainable + fmt::Debug,
+{
+    /// Formats the chain with its tokens rather than their ids, as it is saved.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Chain")
+            .field("map", &Spelled(self, &self.map))
+            .field("order", &self.order)
+            .field("vocabulary", &self.vocabulary)
+            .field("smoothing", &self.smoothing)
+            .field("smoothed_sampling", &self.smoothed_sampling)
+            .field("backoff", &self.backoff)
+            .field("sampling", &self.sampling)
+            .field("overlaps", &self.overlaps)
+            .field("stream_tail", &self.stream_tail)
+            .finish()
+    }
+}
+
+impl<'a, T> fmt::Debug for Spelled<'a, T, HashMap<Vec<Token<Id>>, States<Id>>>
+where
+    T: Chainable + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Spelled(chain, contexts) = *self;
+        f.debug_map()
+            .entries(contexts.iter().map(|(context, states)| {
+                let context: Vec<_> = context.iter().map(|id| chain.resolve(id)).collect();
+                (context, Spelled(chain, states))
+            }))
+            .finish()
+    }
+}
+
+impl<'a, T> fmt::Debug for Spelled<'a, T, States<Id>>
+where
+    T: Chainable + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Spelled(chain, states) = *self;
+        f.debug_map()
+            .entries(states.iter().map(|(id, count)| (chain.resolve(id), count)))
+            .finish()
+    }
+}
+
 impl<T> Chain<T>
 where
     T: Chainable,
@@ -99,111 +387,546 @@ where
         Chain {
             map: {
                 let mut map = HashMap::new();
-                map.insert(vec![None; order], HashMap::new());
+                map.insert(vec![None; order], States::new());
                 map
             },
             order,
+            vocabulary: Vocabulary::new(),
+            smoothing: Smoothing::None,
+            smoothed_sampling: false,
+            backoff: Backoff::None,
+            sampling: Sampling::default(),
+            overlaps: None,
+            stream_tail: Vec::new(),
+            levels: Cache::default(),
+            endless: Cache::default(),
         }
     }
 
     /// Determines whether or not the chain is empty. A chain is considered empty if nothing has
     /// been fed into it.
     pub fn is_empty(&self) -> bool {
-        self.map[&vec![None; self.order]].is_empty()
+        // Streams never start from the empty context, but every token fed is in the vocabulary.
+        self.vocabulary.is_empty()
     }
 
     /// Feeds the chain a collection of tokens. This operation is `O(n)` where `n` is the number of
     /// tokens to be fed into the chain.
     pub fn feed<S: AsRef<[T]>>(&mut self, tokens: S) -> &mut Chain<T> {
-        let tokens = tokens.as_ref();
-        if tokens.is_empty() {
+        self.feed_iter(tokens.as_ref().iter().cloned())
+    }
+
+    /// Feeds the chain a collection of tokens as they are produced by an iterator, without
+    /// collecting them first. This is otherwise the same as `feed`.
+    pub fn feed_iter<I: IntoIterator<Item = T>>(&mut self, tokens: I) -> &mut Chain<T> {
+        let mut context = vec![None; self.order];
+        let mut ids = Vec::new();
+        for token in tokens {
+            let id = self.intern(token, 1);
+            self.add_transition(&context, Some(id));
+            if self.overlaps.is_some() {
+                ids.push(id);
+            }
+            context.remove(0);
+            context.push(Some(id));
+        }
+        if context[self.order - 1].is_none() {
             return self;
         }
-        let mut toks = vec![None; self.order];
-        toks.extend(tokens.iter().map(|token| Some(token.clone())));
-        toks.push(None);
+        self.add_transition(&context, None);
+        self.vocabulary.add(None, 1);
+        self.index(&ids, false);
+        self.levels.clear();
+        self.endless.clear();
+        self
+    }
+
+    /// Adds the given count to a token of the vocabulary, adding the token if it is new, and gets
+    /// its id.
+    fn intern(&mut self, token: T, count: usize) -> Id {
+        to_id(self.vocabulary.add(Some(token), count))
+    }
+
+    /// Counts a transition out of the given context. This only allocates for a new context.
+    fn add_transition(&mut self, context: &[Token<Id>], next: Token<Id>) {
+        match self.map.get_mut(context) {
+            Some(states) => {
+                states.add(next, 1);
+            }
+            None => {
+                let mut states = States::new();
+                states.add(next, 1);
+                self.map.insert(context.to_vec(), states);
+            }
+        }
+    }
+
+    /// Counts the transition at the end of every window of `order + 1` of the given tokens.
+    fn add_windows(&mut self, toks: &[Token<Id>]) {
         for p in toks.windows(self.order + 1) {
-            self.map
-                .entry(p[0..self.order].to_vec())
-                .or_insert_with(HashMap::new);
-            self.map
-                .get_mut(&p[0..self.order].to_vec())
-                .unwrap()
-                .add(p[self.order].clone(), 1);
+            self.add_transition(&p[0..self.order], p[self.order]);
         }
-        self
+        self.levels.clear();
+        self.endless.clear();
     }
 
-    /// Generates a collection of tokens from the chain. This operation is `O(mn)` where `m` is the
-    /// length of the generated collection, and `n` is the number of possible states from a given
-    /// state.
-    pub fn generate(&self) -> Vec<T> {
-        let mut ret = Vec::new();
-        let mut curs = vec![None; self.order];
-        loop {
-            let next = self.map[&curs].next();
-            curs = curs[1..self.order].to_vec();
-            curs.push(next.clone());
-            if let Some(next) = next {
-                ret.push(next)
-            };
-            if curs[self.order - 1].is_none() {
-                break;
+    /// Adds the tokens with the given ids to the overlap index, if any, either as a new sequence
+    /// or as the continuation of the last one.
+    fn index(&mut self, ids: &[Id], continued: bool) {
+        if let Some(ref mut overlaps) = self.overlaps {
+            overlaps.add(ids.iter().map(|&id| id as usize), continued);
+        }
+    }
+
+    /// Gets the id of the given token, or `UNKNOWN` if it was never fed.
+    fn id(&self, token: &Token<T>) -> Token<Id> {
+        token.as_ref().map(|_| {
+            self.vocabulary
+                .position(token)
+                .map_or(UNKNOWN, |position| position as Id)
+        })
+    }
+
+    /// Gets the ids of the given tokens.
+    fn ids(&self, tokens: &[Token<T>]) -> Vec<Token<Id>> {
+        tokens.iter().map(|token| self.id(token)).collect()
+    }
+
+    /// Gets the token with the given id.
+    fn token(&self, id: Id) -> &T {
+        self.vocabulary.token(id as usize).as_ref().unwrap()
+    }
+
+    /// Gets the token with the given id, if any.
+    fn resolve(&self, id: &Token<Id>) -> Option<&T> {
+        id.map(|id| self.token(id))
+    }
+
+    /// Gets the tokens with the given ids.
+    fn resolve_all(&self, ids: &[Token<Id>]) -> Vec<Token<T>> {
+        ids.iter().map(|id| self.resolve(id).cloned()).collect()
+    }
+
+    /// Iterates over the contexts of the chain and the counts of their successors, spelling out
+    /// every token.
+    fn transitions(&self) -> impl Iterator<Item = (Vec<Token<T>>, States<T>)> + '_ {
+        self.map.iter().map(move |(context, states)| {
+            let mut resolved = States::new();
+            for (id, count) in states.iter() {
+                resolved.add(self.resolve(id).cloned(), *count);
+            }
+            (self.resolve_all(context), resolved)
+        })
+    }
+
+    /// Creates an empty chain of the same order with the same settings for smoothing, backoff and
+    /// sampling.
+    fn settings(&self) -> Chain<T> {
+        let mut chain = Chain::of_order(self.order);
+        chain.smoothing = self.smoothing;
+        chain.smoothed_sampling = self.smoothed_sampling;
+        chain.backoff = self.backoff;
+        chain.sampling = self.sampling;
+        chain
+    }
+
+    /// Creates a chain with the settings of this one from the given transitions, and a vocabulary
+    /// in the order of the given one.
+    fn assemble<I>(&self, contexts: I, vocabulary: &Vocabulary<T>) -> Chain<T>
+    where
+        I: IntoIterator<Item = (Vec<Token<T>>, States<T>)>,
+    {
+        let mut chain = self.clone();
+        for (token, _) in vocabulary.iter() {
+            chain.vocabulary.add(token.clone(), 0);
+        }
+        for (context, states) in contexts {
+            let context = context
+                .into_iter()
+                .map(|token| token.map(|token| chain.intern(token, 0)))
+                .collect();
+            let mut interned = States::new();
+            // Every token is counted in the vocabulary each time it follows a context.
+            for (token, count) in states.tokens {
+                let id = match token {
+                    Some(token) => Some(chain.intern(token, count)),
+                    None => {
+                        chain.vocabulary.add(None, count);
+                        None
+                    }
+                };
+                interned.add(id, count);
             }
+            chain.map.insert(context, interned);
         }
-        ret
+        chain
+    }
+
+    /// Surrounds a collection of tokens with the start and end markers used for every sequence in
+    /// the chain.
+    fn pad(&self, tokens: &[T]) -> Vec<Token<T>> {
+        let mut toks = vec![None; self.order];
+        toks.extend(tokens.iter().map(|token| Some(token.clone())));
+        toks.push(None);
+        toks
+    }
+
+    /// Gets the order of the chain, i.e. the number of previous tokens used for each mapping.
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// Generates a collection of tokens from the chain. This operation is `O(m log n)` where `m` is
+    /// the length of the generated collection, and `n` is the number of possible states from a
+    /// given state. The first generation after the chain is fed or its settings change also works
+    /// out which contexts can reach the end of a sequence, which takes time linear in the number of
+    /// transitions of the chain. Generation stops early if the end of a sequence can no longer be
+    /// reached, as on chains fed with `feed_stream` or `feed_cycle`. The cut off collection is
+    /// returned as if it were complete, though without smoothing or backoff its `log_likelihood` is
+    /// then negative infinity.
+    pub fn generate(&self) -> Vec<T> {
+        self.generate_with_rng(&mut thread_rng())
+    }
+
+    /// Generates a collection of tokens from the chain using the given random number generator.
+    /// Seeding the generator makes the output reproducible.
+    pub fn generate_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<T> {
+        self.generate_from_context(vec![None; self.order], Vec::new(), rng)
     }
 
     /// Generates a collection of tokens from the chain, starting with the given token. This
-    /// operation is O(mn) where m is the length of the generated collection, and n is the number
-    /// of possible states from a given state. This returns an empty vector if the token is not
-    /// found.
+    /// operation is O(m log n) where m is the length of the generated collection, and n is the
+    /// number of possible states from a given state, as in `generate`. This returns an empty vector
+    /// if the token is not found. Unless backoff is enabled, the token is only found if it has
+    /// begun a sequence.
     pub fn generate_from_token(&self, token: T) -> Vec<T> {
+        self.generate_from_token_with_rng(token, &mut thread_rng())
+    }
+
+    /// Generates a collection of tokens from the chain, starting with the given token and using
+    /// the given random number generator. This returns an empty vector if the token is not found.
+    pub fn generate_from_token_with_rng<R: Rng + ?Sized>(&self, token: T, rng: &mut R) -> Vec<T> {
+        let token = Some(token);
         let mut curs = vec![None; self.order - 1];
-        curs.push(Some(token.clone()));
-        if !self.map.contains_key(&curs) {
+        curs.push(self.id(&token));
+        let found = match self.backoff {
+            Backoff::None => self.map.contains_key(&curs),
+            _ => self.counts(&curs[self.order - 1..]).is_some(),
+        };
+        if !found {
             return Vec::new();
         }
-        let mut ret = vec![token];
-        loop {
-            let next = self.map[&curs].next();
-            curs = curs[1..self.order].to_vec();
-            curs.push(next.clone());
-            if let Some(next) = next {
-                ret.push(next)
-            };
-            if curs[self.order - 1].is_none() {
-                break;
+        self.generate_from_context(curs, token.into_iter().collect(), rng)
+    }
+
+    /// Generates a collection of tokens from the chain that continues the given prefix from
+    /// anywhere within a sequence. The last `order` tokens of the prefix are used as the context,
+    /// and a shorter prefix matches every context that ends with it. The returned collection
+    /// starts with the prefix, and an empty prefix starts a new sequence. This returns `None` if
+    /// the context was never seen (unless backoff is enabled and a shorter context was).
+    pub fn generate_from_prefix(&self, prefix: &[T]) -> Option<Vec<T>> {
+        self.generate_from_prefix_with_rng(prefix, &mut thread_rng())
+    }
+
+    /// Generates a collection of tokens from the chain that continues the given prefix, using the
+    /// given random number generator. See `generate_from_prefix` for details.
+    pub fn generate_from_prefix_with_rng<R: Rng + ?Sized>(
+        &self,
+        prefix: &[T],
+        rng: &mut R,
+    ) -> Option<Vec<T>> {
+        if prefix.is_empty() {
+            return Some(self.generate_with_rng(rng));
+        }
+        let start = prefix.len().saturating_sub(self.order);
+        let curs: Vec<_> = prefix[start..]
+            .iter()
+            .map(|token| self.id(&Some(token.clone())))
+            .collect();
+        self.successor_counts(&curs)?;
+        Some(self.generate_from_context(curs, prefix.to_vec(), rng))
+    }
+
+    /// Walks the chain from the given context until the end of a sequence is reached, or can no
+    /// longer be reached, appending every generated token to `ret`. The context may be shorter
+    /// than the order of the chain, in which case it grows with every generated token until it
+    /// is long enough.
+    fn generate_from_context<R: Rng + ?Sized>(
+        &self,
+        mut curs: Vec<Token<Id>>,
+        mut ret: Vec<T>,
+        rng: &mut R,
+    ) -> Vec<T> {
+        let mut ends = Ends::new();
+        // Stop rather than loop forever once the end of a sequence is out of reach.
+        while self.can_end(&curs, &mut ends) {
+            let next = self.next_token(&curs, rng);
+            if curs.len() == self.order {
+                curs.remove(0);
+            }
+            curs.push(next);
+            match next {
+                Some(next) => ret.push(self.token(next).clone()),
+                None => break,
             }
         }
         ret
     }
 
+    /// Determines whether the end of a sequence can be reached from the given context, given the
+    /// successors that the settings of the chain allow to be sampled. The contexts that follow it
+    /// are only explored with the given tracker if necessary.
+    fn can_end(&self, context: &[Token<Id>], ends: &mut Ends<Vec<Token<Id>>>) -> bool {
+        self.known_end(context).unwrap_or_else(|| {
+            ends.reachable(&context.to_vec(), |context| match self.known_end(context) {
+                Some(true) => None,
+                Some(false) => Some(Vec::new()),
+                None => self.following(context),
+            })
+        })
+    }
+
+    /// Determines whether the end of a sequence can be reached from the given context, if that
+    /// is known without exploring the contexts that follow it.
+    fn known_end(&self, context: &[Token<Id>]) -> Option<bool> {
+        if self.samples_smoothed(context) {
+            // Every token of the vocabulary may follow, so the end may too if it is in there.
+            if self.vocabulary.position(&None).is_none() {
+                return Some(false);
+            }
+            if self.sampling.keeps_support() {
+                return Some(true);
+            }
+            None
+        } else if self.map.contains_key(context) {
+            // Seen contexts are worked out all at once.
+            let endless = self.endless();
+            Some(endless.is_empty() || !endless.contains(context))
+        } else {
+            None
+        }
+    }
+
+    /// Gets the seen contexts from which the end of a sequence can never be reached when
+    /// sampling from the counts, computing them if necessary.
+    fn endless(&self) -> &HashSet<Vec<Token<Id>>> {
+        self.endless.get_or_init(|| {
+            ends::endless(self.map.keys().cloned().collect(), |context| {
+                self.following(context)
+            })
+        })
+    }
+
+    /// Gets the contexts that may follow the given one when sampling, or `None` if the sequence
+    /// may end right after it.
+    fn following(&self, context: &[Token<Id>]) -> Option<Vec<Vec<Token<Id>>>> {
+        let candidates = self.candidates(context);
+        let weights = candidates.iter().map(|&(_, weight)| weight).collect();
+        let support = self.sampling.support(weights);
+        // A context without successors ends the sequence.
+        if support.is_empty() {
+            return None;
+        }
+        let start = if context.len() == self.order { 1 } else { 0 };
+        support
+            .into_iter()
+            .map(|i| {
+                let mut following = context[start..].to_vec();
+                following.push(Some((*candidates[i].0)?));
+                Some(following)
+            })
+            .collect()
+    }
+
+    /// Samples the token following the given context, which may be shorter than the order of the
+    /// chain. This ends the sequence if the context has no successors.
+    fn next_token<R: Rng + ?Sized>(&self, context: &[Token<Id>], rng: &mut R) -> Token<Id> {
+        if self.sampling.is_plain() && !self.samples_smoothed(context) {
+            return self
+                .successor_counts(context)
+                .and_then(|states| states.next(rng));
+        }
+        self.sampling
+            .next(self.candidates(context), rng)
+            .unwrap_or(None)
+    }
+
+    /// Samples the token following the given context, with the weight of each successor
+    /// multiplied by the given function. This returns `None` if no successor keeps a positive
+    /// weight.
+    fn next_token_weighted<R, F>(
+        &self,
+        context: &[Token<Id>],
+        mut weight: F,
+        rng: &mut R,
+    ) -> Option<Token<Id>>
+    where
+        R: Rng + ?Sized,
+        F: FnMut(&Token<Id>) -> f64,
+    {
+        let mut candidates = self.candidates(context);
+        for candidate in &mut candidates {
+            candidate.1 *= weight(candidate.0);
+        }
+        self.sampling.next(candidates, rng)
+    }
+
+    /// Determines whether the successors of the given context are sampled from the smoothed
+    /// distribution.
+    fn samples_smoothed(&self, context: &[Token<Id>]) -> bool {
+        self.smoothed_sampling && self.smoothing != Smoothing::None && context.len() == self.order
+    }
+
+    /// Gets the counts of the successors of the given context, backing off to a shorter context
+    /// if enabled. This returns `None` if the context has no successors.
+    fn successor_counts(&self, context: &[Token<Id>]) -> Option<&States<Id>> {
+        match self.counts(context) {
+            Some(states) if !states.is_empty() => Some(states),
+            _ if self.backoff != Backoff::None => self.backed_off_counts(context),
+            _ => None,
+        }
+    }
+
+    /// Gets the successors of the given context that may be sampled, weighted by how likely they
+    /// are before the sampling settings are applied.
+    fn candidates(&self, context: &[Token<Id>]) -> Vec<(&Token<Id>, f64)> {
+        if self.samples_smoothed(context) {
+            return self.smoothed_successors(context);
+        }
+        self.successor_counts(context)
+            .map_or_else(Vec::new, |states| {
+                states.iter().map(|(t, c)| (t, *c as f64)).collect()
+            })
+    }
+
     /// Merges 2 chains (self and other) into self, consuming the other one. Both chains must be of
     /// the same order. This method is useful when you want to speed up chain building - chains
-    /// built independently (e.g. in parallel with rayon) can be merged into a final one.
+    /// built independently (e.g. in parallel with rayon, as `par_feed_all` does with the `rayon`
+    /// feature) can be merged into a final one. The sequences indexed by the other chain are only
+    /// kept if self retains an index too.
     pub fn merge(&mut self, other: Chain<T>) -> &Chain<T> {
         assert!(self.order == other.order);
 
+        let mut positions = Vec::with_capacity(other.vocabulary.len());
+        for (token, count) in other.vocabulary {
+            positions.push(self.vocabulary.add(token, count));
+        }
+        // The ids of the other chain are positions in its own vocabulary.
+        let id = |token: &Token<Id>| token.map(|id| to_id(positions[id as usize]));
         for (tokens, next) in other.map {
-            let states = self.map.entry(tokens).or_insert_with(HashMap::new);
+            let states = self
+                .map
+                .entry(tokens.iter().map(&id).collect())
+                .or_default();
 
-            for (token, count) in next {
-                states.add(token, count);
+            for (token, count) in next.tokens {
+                states.add(id(&token), count);
             }
         }
+        if let (Some(overlaps), Some(other)) = (self.overlaps.as_mut(), other.overlaps) {
+            overlaps.merge(other, |position| positions[position]);
+        }
+        self.levels.clear();
+        self.endless.clear();
 
         self
     }
 
-    /// Produces an infinite iterator of generated token collections.
-    pub fn iter(&self) -> InfiniteChainIterator<T> {
-        InfiniteChainIterator { chain: self }
+    /// Gets every possible successor of the given context along with its probability, in the order
+    /// the successors were first fed into the chain. The context must contain exactly `order`
+    /// tokens, where `None` marks the start of a sequence (e.g. `[None, Some(a)]` is the context
+    /// after `a` begins a sequence in a chain of order 2). A `None` successor marks the end of a
+    /// sequence. This returns an empty vector if the context was never seen.
+    ///
+    /// When smoothing or backoff is enabled, every token of the chain is a possible successor of
+    /// any context.
+    pub fn successors(&self, context: &[Option<T>]) -> Vec<(Option<&T>, f64)> {
+        assert_eq!(context.len(), self.order);
+        let context = self.ids(context);
+        if self.smoothing != Smoothing::None || self.backoff != Backoff::None {
+            return self
+                .smoothed_successors(&context)
+                .into_iter()
+                .map(|(token, p)| (self.resolve(token), p))
+                .collect();
+        }
+        match self.map.get(&context) {
+            Some(states) => {
+                let sum = states.sum() as f64;
+                states
+                    .iter()
+                    .map(|(token, count)| (self.resolve(token), *count as f64 / sum))
+                    .collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Gets the probability that `next` follows the given context, where a `next` of `None` is the
+    /// probability that the sequence ends. The context is laid out as for `successors`. Without
+    /// smoothing or backoff, this returns `0.0` if the transition was never seen.
+    pub fn probability(&self, context: &[Option<T>], next: Option<&T>) -> f64 {
+        assert_eq!(context.len(), self.order);
+        self.smoothed_probability(&self.ids(context), &self.id(&next.cloned()))
+    }
+
+    /// Computes the natural logarithm of the probability that the chain generates exactly the
+    /// given collection of tokens, including the transitions out of the start of the sequence and
+    /// into its end. Without smoothing or backoff, this returns negative infinity if any transition
+    /// was never seen.
+    pub fn log_likelihood<S: AsRef<[T]>>(&self, tokens: S) -> f64 {
+        let toks = self.ids(&self.pad(tokens.as_ref()));
+        toks.windows(self.order + 1)
+            .map(|p| {
+                self.smoothed_probability(&p[0..self.order], &p[self.order])
+                    .ln()
+            })
+            .sum()
+    }
+
+    /// Computes the [cross-entropy](https://en.wikipedia.org/wiki/Cross_entropy) of the chain on
+    /// the given collections of tokens, in bits per transition. Every token and the end of every
+    /// collection counts as one transition, and empty collections are skipped as in `feed`. Lower
+    /// is better: this is the standard way to compare chains on held-out data. This returns
+    /// infinity if any transition was never seen, and `NaN` if there is nothing to evaluate.
+    pub fn cross_entropy<S: AsRef<[T]>>(&self, sequences: &[S]) -> f64 {
+        Source::Chain(self).cross_entropy(sequences)
+    }
+
+    /// Computes the [perplexity](https://en.wikipedia.org/wiki/Perplexity) of the chain on the
+    /// given collections of tokens, i.e. two to the power of their cross-entropy.
+    pub fn perplexity<S: AsRef<[T]>>(&self, sequences: &[S]) -> f64 {
+        self.cross_entropy(sequences).exp2()
+    }
+
+    /// Produces an infinite iterator of generated token collections. As with `generate`, a
+    /// collection is cut off without notice once the end of a sequence can no longer be reached.
+    pub fn iter(&self) -> InfiniteChainIterator<'_, T> {
+        self.iter_with_rng(thread_rng())
+    }
+
+    /// Produces an infinite iterator of generated token collections that draws from the given
+    /// random number generator.
+    pub fn iter_with_rng<R: Rng>(&self, rng: R) -> InfiniteChainIterator<'_, T, R> {
+        InfiniteChainIterator {
+            chain: Source::Chain(self),
+            rng,
+        }
     }
 
     /// Produces an iterator for the specified number of generated token collections.
-    pub fn iter_for(&self, size: usize) -> SizedChainIterator<T> {
-        SizedChainIterator { chain: self, size }
+    pub fn iter_for(&self, size: usize) -> SizedChainIterator<'_, T> {
+        self.iter_for_with_rng(size, thread_rng())
+    }
+
+    /// Produces an iterator for the specified number of generated token collections that draws
+    /// from the given random number generator.
+    pub fn iter_for_with_rng<R: Rng>(&self, size: usize, rng: R) -> SizedChainIterator<'_, T, R> {
+        SizedChainIterator {
+            chain: Source::Chain(self),
+            size,
+            rng,
+        }
     }
 
     /// Create a graph using `petgraph` from the markov chain.
@@ -221,27 +944,30 @@ where
                 let mut state = state.clone();
                 state.remove(0);
 
-                for next in nexts {
+                for next in nexts.iter() {
                     let mut next_state = state.clone();
-                    next_state.push(next.0.clone());
+                    next_state.push(next.0);
                     states.push(next_state);
                 }
 
                 states
             })
             .unique()
-            .map(|state| (state.clone(), graph.add_node(state)))
+            .map(|state| {
+                let node = graph.add_node(self.resolve_all(&state));
+                (state, node)
+            })
             .collect::<HashMap<_, _>>();
 
         // Create all edges, and add them to the graph.
         self.map
             .iter()
             .flat_map(|(state, nexts)| {
-                let sum = nexts.iter().map(|(_, p)| p).sum::<usize>() as f64;
+                let sum = nexts.sum() as f64;
 
                 nexts
                     .iter()
-                    .map(|(next, p)| (state.clone(), next.clone(), *p as f64 / sum))
+                    .map(|(next, p)| (state.clone(), *next, *p as f64 / sum))
                     .collect::<Vec<_>>()
             })
             .for_each(|(state, next, p)| {
@@ -288,25 +1014,47 @@ where
 impl Chain<String> {
     /// Feeds a string of text into the chain.
     pub fn feed_str(&mut self, string: &str) -> &mut Chain<String> {
-        self.feed(&string.split(' ').map(|s| s.to_owned()).collect::<Vec<_>>())
+        self.feed(string.split(' ').map(|s| s.to_owned()).collect::<Vec<_>>())
     }
 
     /// Feeds a properly formatted file into the chain. This file should be formatted such that
     /// each line is a new sentence. Punctuation may be included if it is desired.
     pub fn feed_file<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Chain<String>> {
-        let reader = BufReader::new(File::open(path)?);
+        self.feed_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Feeds text from a reader into the chain, one line at a time, where each line is a new
+    /// sentence as in `feed_file`. This reads from standard input, sockets or decompressed
+    /// streams without holding more than a line in memory.
+    pub fn feed_reader<R: BufRead>(&mut self, reader: R) -> Result<&mut Chain<String>> {
         for line in reader.lines() {
-            let line = line?;
-            let words = line
-                .split_whitespace()
-                .filter(|word| !word.is_empty())
-                .map(|s| s.to_owned())
-                .collect::<Vec<_>>();
-            self.feed(&words);
+            self.feed_iter(Chain::words(&line?));
         }
         Ok(self)
     }
 
+    /// Splits a line of a file into the words fed into the chain.
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|s| s.to_owned()).collect()
+    }
+
+    /// Computes the cross-entropy of the chain, in bits per transition, on a held-out file
+    /// formatted as for `feed_file`.
+    pub fn cross_entropy_file<P: AsRef<Path>>(&self, path: P) -> Result<f64> {
+        Source::Chain(self).cross_entropy_file(path)
+    }
+
+    /// Computes the perplexity of the chain on a held-out file formatted as for `feed_file`.
+    pub fn perplexity_file<P: AsRef<Path>>(&self, path: P) -> Result<f64> {
+        self.cross_entropy_file(path).map(f64::exp2)
+    }
+
+    /// Computes the natural logarithm of the probability that the chain generates the given string
+    /// of text, split on spaces as in `feed_str`.
+    pub fn log_likelihood_str(&self, string: &str) -> f64 {
+        self.log_likelihood(string.split(' ').map(|s| s.to_owned()).collect::<Vec<_>>())
+    }
+
     /// Converts the output of `generate(...)` on a String chain to a single String.
     fn vec_to_string(vec: Vec<String>) -> String {
         let mut ret = String::new();
@@ -326,44 +1074,125 @@ impl Chain<String> {
         Chain::vec_to_string(self.generate())
     }
 
+    /// Generates a random string of text using the given random number generator.
+    pub fn generate_str_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> String {
+        Chain::vec_to_string(self.generate_with_rng(rng))
+    }
+
     /// Generates a random string of text starting with the desired token. This returns an empty
     /// string if the token is not found.
     pub fn generate_str_from_token(&self, string: &str) -> String {
         Chain::vec_to_string(self.generate_from_token(string.to_owned()))
     }
 
+    /// Generates a random string of text starting with the desired token, using the given random
+    /// number generator. This returns an empty string if the token is not found.
+    pub fn generate_str_from_token_with_rng<R: Rng + ?Sized>(
+        &self,
+        string: &str,
+        rng: &mut R,
+    ) -> String {
+        Chain::vec_to_string(self.generate_from_token_with_rng(string.to_owned(), rng))
+    }
+
+    /// Generates a random string of text that continues the given prefix from anywhere within a
+    /// sentence, as in `generate_from_prefix`. The prefix is split on whitespace, and the returned
+    /// string starts with it. This returns `None` if the context was never seen.
+    pub fn generate_str_from_prefix(&self, prefix: &str) -> Option<String> {
+        self.generate_str_from_prefix_with_rng(prefix, &mut thread_rng())
+    }
+
+    /// Generates a random string of text that continues the given prefix, using the given random
+    /// number generator. This returns `None` if the context was never seen.
+    pub fn generate_str_from_prefix_with_rng<R: Rng + ?Sized>(
+        &self,
+        prefix: &str,
+        rng: &mut R,
+    ) -> Option<String> {
+        let prefix = prefix
+            .split_whitespace()
+            .map(|s| s.to_owned())
+            .collect::<Vec<_>>();
+        self.generate_from_prefix_with_rng(&prefix, rng)
+            .map(Chain::vec_to_string)
+    }
+
     /// Produces an infinite iterator of generated strings.
-    pub fn str_iter(&self) -> InfiniteChainStringIterator {
+    pub fn str_iter(&self) -> InfiniteChainStringIterator<'_> {
+        self.str_iter_with_rng(thread_rng())
+    }
+
+    /// Produces an infinite iterator of generated strings that draws from the given random number
+    /// generator.
+    pub fn str_iter_with_rng<R: Rng>(&self, rng: R) -> InfiniteChainStringIterator<'_, R> {
         let vec_to_string: fn(Vec<String>) -> String = Chain::vec_to_string;
-        self.iter().map(vec_to_string)
+        self.iter_with_rng(rng).map(vec_to_string)
     }
 
     /// Produces a sized iterator of generated strings.
-    pub fn str_iter_for(&self, size: usize) -> SizedChainStringIterator {
+    pub fn str_iter_for(&self, size: usize) -> SizedChainStringIterator<'_> {
+        self.str_iter_for_with_rng(size, thread_rng())
+    }
+
+    /// Produces a sized iterator of generated strings that draws from the given random number
+    /// generator.
+    pub fn str_iter_for_with_rng<R: Rng>(
+        &self,
+        size: usize,
+        rng: R,
+    ) -> SizedChainStringIterator<'_, R> {
         let vec_to_string: fn(Vec<String>) -> String = Chain::vec_to_string;
-        self.iter_for(size).map(vec_to_string)
+        self.iter_for_with_rng(size, rng).map(vec_to_string)
+    }
+}
+
+impl<T, S> Extend<S> for Chain<T>
+where
+    T: Chainable,
+    S: AsRef<[T]>,
+{
+    /// Feeds every collection of tokens of the iterator into the chain.
+    fn extend<I: IntoIterator<Item = S>>(&mut self, iter: I) {
+        for tokens in iter {
+            self.feed(tokens);
+        }
+    }
+}
+
+impl<T, S> FromIterator<S> for Chain<T>
+where
+    T: Chainable,
+    S: AsRef<[T]>,
+{
+    /// Creates a new Markov chain of order 1 fed with every collection of tokens of the iterator.
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Chain<T> {
+        let mut chain = Chain::new();
+        chain.extend(iter);
+        chain
     }
 }
 
 /// A sized iterator over a Markov chain of strings.
-pub type SizedChainStringIterator<'a> =
-    Map<SizedChainIterator<'a, String>, fn(Vec<String>) -> String>;
+pub type SizedChainStringIterator<'a, R = ThreadRng> =
+    Map<SizedChainIterator<'a, String, R>, fn(Vec<String>) -> String>;
 
 /// A sized iterator over a Markov chain.
-pub struct SizedChainIterator<'a, T: Chainable + 'a> {
-    chain: &'a Chain<T>,
+pub struct SizedChainIterator<'a, T: Chainable + 'a, R: Rng = ThreadRng> {
+    chain: Source<'a, T>,
     size: usize,
+    rng: R,
 }
 
-impl<'a, T> Iterator for SizedChainIterator<'a, T>
+impl<'a, T, R> Iterator for SizedChainIterator<'a, T, R>
 where
     T: Chainable + 'a,
+    R: Rng,
 {
     type Item = Vec<T>;
     fn next(&mut self) -> Option<Vec<T>> {
         if self.size > 0 {
             self.size -= 1;
-            Some(self.chain.generate())
+            Some(self.chain.generate_with_rng(&mut self.rng))
         } else {
             None
         }
@@ -375,66 +1204,260 @@ where
 }
 
 /// An infinite iterator over a Markov chain of strings.
-pub type InfiniteChainStringIterator<'a> =
-    Map<InfiniteChainIterator<'a, String>, fn(Vec<String>) -> String>;
+pub type InfiniteChainStringIterator<'a, R = ThreadRng> =
+    Map<InfiniteChainIterator<'a, String, R>, fn(Vec<String>) -> String>;
 
 /// An infinite iterator over a Markov chain.
-pub struct InfiniteChainIterator<'a, T: Chainable + 'a> {
-    chain: &'a Chain<T>,
+pub struct InfiniteChainIterator<'a, T: Chainable + 'a, R: Rng = ThreadRng> {
+    chain: Source<'a, T>,
+    rng: R,
 }
 
-impl<'a, T> Iterator for InfiniteChainIterator<'a, T>
+impl<'a, T, R> Iterator for InfiniteChainIterator<'a, T, R>
 where
     T: Chainable + 'a,
+    R: Rng,
 {
     type Item = Vec<T>;
     fn next(&mut self) -> Option<Vec<T>> {
-        Some(self.chain.generate())
+        Some(self.chain.generate_with_rng(&mut self.rng))
     }
 }
 
-/// A collection of states for the Markov chain.
-trait States<T: PartialEq> {
-    /// Adds a state to this states collection.
-    fn add(&mut self, token: Token<T>, count: usize);
-    /// Gets the next state from this collection of states.
-    fn next(&self) -> Token<T>;
+/// A collection of states for the Markov chain. States are kept in the order they were first
+/// added, which makes sampling from a seeded random number generator reproducible.
+#[derive(Clone, Debug)]
+struct States<T: Chainable> {
+    tokens: Vec<(Token<T>, usize)>,
+    index: HashMap<Token<T>, usize>,
+    /// The running totals of the counts, which are built on the first sample after a change.
+    cumulative: Cache<Vec<u64>>,
 }
 
-impl<T> States<T> for HashMap<Token<T>, usize>
+impl<T> States<T>
 where
     T: Chainable,
 {
-    fn add(&mut self, token: Token<T>, count: usize) {
-        match self.entry(token) {
-            Occupied(mut e) => *e.get_mut() += count,
-            Vacant(e) => {
-                e.insert(count);
+    /// Creates an empty collection of states.
+    fn new() -> States<T> {
+        States {
+            tokens: Vec::new(),
+            index: HashMap::new(),
+            cumulative: Cache::default(),
+        }
+    }
+
+    /// Determines whether or not any state has been added to this collection.
+    fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Iterates over the states and their counts in the order they were first added.
+    fn iter(&self) -> slice::Iter<'_, (Token<T>, usize)> {
+        self.tokens.iter()
+    }
+
+    /// Gets the number of distinct states in this collection.
+    fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Gets the total count of all states in this collection.
+    fn sum(&self) -> usize {
+        self.cumulative().last().map_or(0, |&sum| sum as usize)
+    }
+
+    /// Gets the running totals of the counts of the states, in order.
+    fn cumulative(&self) -> &[u64] {
+        self.cumulative.get_or_init(|| {
+            let mut sum = 0;
+            self.tokens
+                .iter()
+                .map(|&(_, count)| {
+                    sum += count as u64;
+                    sum
+                })
+                .collect()
+        })
+    }
+
+    /// Gets the count of the given state in this collection.
+    fn count(&self, token: &Token<T>) -> usize {
+        self.index.get(token).map_or(0, |&i| self.tokens[i].1)
+    }
+
+    /// Adds a state to this states collection, and gets its position.
+    fn add(&mut self, token: Token<T>, count: usize) -> usize {
+        self.cumulative.clear();
+        match self.index.get(&token) {
+            Some(&i) => {
+                self.tokens[i].1 += count;
+                i
+            }
+            None => {
+                let i = self.tokens.len();
+                self.index.insert(token.clone(), i);
+                self.tokens.push((token, count));
+                i
             }
         }
     }
 
-    fn next(&self) -> Token<T> {
-        let mut sum = 0;
-        for &value in self.values() {
-            sum += value;
+    /// Reorders the states in this collection according to the given key.
+    fn sort_by_key<K: Ord, F: FnMut(&Token<T>) -> K>(&mut self, mut key: F) {
+        self.tokens.sort_by_key(|(token, _)| key(token));
+        self.cumulative.clear();
+        for (i, (token, _)) in self.tokens.iter().enumerate() {
+            self.index.insert(token.clone(), i);
         }
-        let mut rng = thread_rng();
-        let cap = rng.gen_range(0..sum);
-        sum = 0;
-        for (key, &value) in self.iter() {
-            sum += value;
-            if sum > cap {
-                return key.clone();
+    }
+
+    /// Gets the next state from this collection of states, drawing from the given generator.
+    /// Sampling is done over `u64` so that the result does not depend on the platform, and takes
+    /// logarithmic time by a binary search of the running totals of the counts.
+    fn next<R: Rng + ?Sized>(&self, rng: &mut R) -> Token<T> {
+        let cumulative = self.cumulative();
+        let cap = rng.gen_range(0..cumulative.last().cloned().unwrap_or(0));
+        let i = cumulative.partition_point(|&sum| sum <= cap);
+        self.tokens[i].0.clone()
+    }
+}
+
+impl<T> Default for States<T>
+where
+    T: Chainable,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> PartialEq for States<T>
+where
+    T: Chainable,
+{
+    fn eq(&self, other: &States<T>) -> bool {
+        self.tokens.len() == other.tokens.len()
+            && self
+                .tokens
+                .iter()
+                .all(|(token, count)| match other.index.get(token) {
+                    Some(&i) => other.tokens[i].1 == *count,
+                    None => false,
+                })
+    }
+}
+
+impl<T> Serialize for States<T>
+where
+    T: Chainable + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.tokens.len()))?;
+        for (token, count) in &self.tokens {
+            map.serialize_entry(token, count)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de, T> Deserialize<'de> for States<T>
+where
+    T: Chainable + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct StatesVisitor<T>(PhantomData<T>);
+
+        impl<'de, T> Visitor<'de> for StatesVisitor<T>
+        where
+            T: Chainable + Deserialize<'de>,
+        {
+            type Value = States<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of states to counts")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut access: A,
+            ) -> std::result::Result<States<T>, A::Error> {
+                let mut states = States::new();
+                while let Some((token, count)) = access.next_entry()? {
+                    states.add(token, count);
+                }
+                Ok(states)
             }
         }
-        unreachable!("The random number generator failed.")
+
+        deserializer.deserialize_map(StatesVisitor(PhantomData))
+    }
+}
+
+/// Picks an index at random, with probability proportional to the weight at that index. This
+/// returns `None` if no weight is positive.
+fn choose<R: Rng + ?Sized>(weights: &[f64], rng: &mut R) -> Option<usize> {
+    let sum: f64 = weights.iter().sum();
+    if sum.is_nan() || sum <= 0.0 {
+        return None;
+    }
+    let cap = rng.gen::<f64>() * sum;
+    let mut acc = 0.0;
+    for (i, &weight) in weights.iter().enumerate() {
+        acc += weight;
+        if acc > cap {
+            return Some(i);
+        }
+    }
+    // Rounding errors can leave the cap just past the last positive weight.
+    weights.iter().rposition(|&weight| weight > 0.0)
+}
+
+/// A value lazily derived from a chain. It is discarded whenever the chain changes or is cloned,
+/// and ignored when comparing chains.
+struct Cache<V>(OnceLock<V>);
+
+impl<V> Cache<V> {
+    /// Gets the cached value, computing it if necessary.
+    fn get_or_init<F: FnOnce() -> V>(&self, f: F) -> &V {
+        self.0.get_or_init(f)
+    }
+
+    /// Discards the cached value.
+    fn clear(&mut self) {
+        self.0.take();
+    }
+}
+
+impl<V> Default for Cache<V> {
+    fn default() -> Self {
+        Cache(OnceLock::new())
+    }
+}
+
+impl<V> Clone for Cache<V> {
+    fn clone(&self) -> Self {
+        Cache::default()
+    }
+}
+
+impl<V> PartialEq for Cache<V> {
+    fn eq(&self, _: &Cache<V>) -> bool {
+        true
+    }
+}
+
+impl<V> fmt::Debug for Cache<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Cache")
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Chain;
+    use super::{to_id, Chain, UNKNOWN};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     #[test]
     fn new() {
@@ -456,6 +1479,26 @@ mod test {
         chain.feed(vec![3, 5, 10]).feed(vec![5, 12]);
     }
 
+    #[test]
+    fn feed_iter() {
+        let mut chain = Chain::of_order(2);
+        chain.feed_iter((1u8..4).map(|i| i * 2)).feed_iter(vec![]);
+        let mut fed = Chain::of_order(2);
+        fed.feed(vec![2u8, 4, 6]);
+        assert_eq!(chain, fed);
+    }
+
+    #[test]
+    fn from_iter() {
+        let mut chain: Chain<u8> = vec![vec![3u8, 5, 10], vec![5, 12]].into_iter().collect();
+        let mut fed = Chain::new();
+        fed.feed(vec![3u8, 5, 10]).feed(vec![5, 12]);
+        assert_eq!(chain, fed);
+        chain.extend(vec![[1u8, 2]]);
+        fed.feed([1u8, 2]);
+        assert_eq!(chain, fed);
+    }
+
     #[test]
     fn generate() {
         let mut chain = Chain::new();
@@ -464,6 +1507,21 @@ mod test {
         assert!([vec![3, 5, 10], vec![3, 5, 12], vec![5, 10], vec![5, 12]].contains(&v));
     }
 
+    #[test]
+    fn generate_after_feed() {
+        let mut chain = Chain::new();
+        chain.feed(vec![1u8]);
+        assert_eq!(chain.generate(), vec![1]);
+        // Sampling must not use the totals cached before the chain was fed again.
+        for _ in 0..999 {
+            chain.feed(vec![2u8]);
+        }
+        assert!(chain.iter_for(20).any(|v| v == vec![2]));
+        let mut clone = chain.clone();
+        clone.feed(vec![3u8]);
+        assert!(chain.iter_for(100).all(|v| v != vec![3]));
+    }
+
     #[test]
     fn generate_for_higher_order() {
         let mut chain = Chain::of_order(2);
@@ -494,6 +1552,157 @@ mod test {
         assert!(v.is_empty());
     }
 
+    #[test]
+    fn generate_with_rng() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![3u8, 5, 10, 5, 12])
+            .feed(vec![5, 3, 10, 12])
+            .feed(vec![10, 3, 12]);
+        let first = chain.generate_with_rng(&mut StdRng::seed_from_u64(7));
+        for _ in 0..10 {
+            assert_eq!(
+                chain.generate_with_rng(&mut StdRng::seed_from_u64(7)),
+                first
+            );
+        }
+    }
+
+    #[test]
+    fn generate_is_independent_of_hashing() {
+        // Successors are sampled in insertion order, so a chain rebuilt from the same corpus (with
+        // freshly seeded hash maps) must reproduce the same output.
+        let build = || {
+            let mut chain = Chain::of_order(2);
+            chain
+                .feed_str("the cat sat on the mat and the cat ate the rat")
+                .feed_str("the dog sat on the cat and the rat ran off")
+                .feed_str("a cat and a dog and a rat sat on the mat");
+            chain
+        };
+        let expected = build()
+            .str_iter_for_with_rng(20, StdRng::seed_from_u64(11))
+            .collect::<Vec<_>>();
+        for _ in 0..10 {
+            let actual = build()
+                .str_iter_for_with_rng(20, StdRng::seed_from_u64(11))
+                .collect::<Vec<_>>();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn iter_with_rng() {
+        let mut chain = Chain::new();
+        chain.feed_str("I like cats and I like dogs and cats like me");
+        let first = chain
+            .str_iter_for_with_rng(5, StdRng::seed_from_u64(3))
+            .collect::<Vec<_>>();
+        let second = chain
+            .str_iter_with_rng(StdRng::seed_from_u64(3))
+            .take(5)
+            .collect::<Vec<_>>();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn generate_from_prefix() {
+        let mut chain = Chain::of_order(2);
+        chain.feed(vec![3u8, 5, 10]).feed(vec![2, 3, 5, 12]);
+        for _ in 0..20 {
+            let v = chain.generate_from_prefix(&[2, 3, 5]).unwrap();
+            assert!([vec![2, 3, 5, 10], vec![2, 3, 5, 12]].contains(&v));
+            let v = chain.generate_from_prefix(&[5]).unwrap();
+            assert!([vec![5, 10], vec![5, 12]].contains(&v));
+        }
+        assert_eq!(chain.generate_from_prefix(&[5, 3]), None);
+        assert_eq!(chain.generate_from_prefix(&[9]), None);
+        assert!(chain.generate_from_prefix(&[]).is_some());
+    }
+
+    #[test]
+    fn generate_str_from_prefix() {
+        let mut chain = Chain::of_order(3);
+        chain
+            .feed_str("we all like cats a lot")
+            .feed_str("I like cats too");
+        for _ in 0..20 {
+            let generated = chain.generate_str_from_prefix("I like").unwrap();
+            assert!(["I like cats a lot", "I like cats too"].contains(&&generated[..]));
+        }
+        assert_eq!(
+            chain
+                .generate_str_from_prefix("so we all like cats")
+                .unwrap(),
+            "so we all like cats a lot"
+        );
+        assert_eq!(chain.generate_str_from_prefix("like dogs"), None);
+    }
+
+    #[test]
+    fn successors() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![3u8, 5, 10])
+            .feed(vec![5, 12])
+            .feed(vec![5, 10]);
+        assert_eq!(
+            chain.successors(&[Some(5)]),
+            vec![(Some(&10), 2.0 / 3.0), (Some(&12), 1.0 / 3.0)]
+        );
+        assert_eq!(chain.successors(&[Some(12)]), vec![(None, 1.0)]);
+        assert!(chain.successors(&[Some(9)]).is_empty());
+    }
+
+    #[test]
+    fn probability() {
+        let mut chain = Chain::of_order(2);
+        chain.feed(vec![3u8, 5, 10]).feed(vec![3, 5, 12]);
+        assert_eq!(chain.probability(&[None, None], Some(&3)), 1.0);
+        assert_eq!(chain.probability(&[Some(3), Some(5)], Some(&10)), 0.5);
+        assert_eq!(chain.probability(&[Some(5), Some(10)], None), 1.0);
+        assert_eq!(chain.probability(&[Some(3), Some(5)], None), 0.0);
+        assert_eq!(chain.probability(&[Some(9), Some(9)], Some(&3)), 0.0);
+    }
+
+    #[test]
+    fn log_likelihood() {
+        let mut chain = Chain::new();
+        chain.feed(vec![3u8, 5, 10]).feed(vec![5, 12]);
+        let expected = (0.5f64 * 1.0 * 0.5 * 1.0).ln();
+        assert!((chain.log_likelihood([3, 5, 10]) - expected).abs() < 1e-12);
+        assert_eq!(chain.log_likelihood([3, 10]), f64::NEG_INFINITY);
+        assert_eq!(chain.log_likelihood([3, 5]), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn log_likelihood_str() {
+        let mut chain = Chain::new();
+        chain.feed_str("I like cats").feed_str("I hate cats");
+        assert!((chain.log_likelihood_str("I like cats") - 0.5f64.ln()).abs() < 1e-12);
+        assert_eq!(chain.log_likelihood_str("cats like I"), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn cross_entropy() {
+        let mut chain = Chain::new();
+        chain.feed(vec![3u8, 5, 10]).feed(vec![5, 12]);
+        let empty: Vec<u8> = Vec::new();
+        // 1 bit for choosing the start, 1 bit for choosing the successor of 5, over 4 transitions.
+        assert_eq!(chain.cross_entropy(&[vec![3, 5, 10], empty]), 0.5);
+        assert_eq!(chain.perplexity(&[vec![3, 5, 10]]), 0.5f64.exp2());
+        assert_eq!(chain.cross_entropy(&[vec![3, 10]]), f64::INFINITY);
+    }
+
+    #[test]
+    fn perplexity_file() {
+        let mut chain = Chain::new();
+        chain.feed_file("test").unwrap();
+        let perplexity = chain.perplexity_file("test").unwrap();
+        assert!(perplexity >= 1.0 && perplexity.is_finite());
+        assert_eq!(chain.cross_entropy_file("test").unwrap().exp2(), perplexity);
+    }
+
     #[test]
     fn iter() {
         let mut chain = Chain::new();
@@ -514,6 +1723,17 @@ mod test {
         chain.feed_str("I like cats and dogs");
     }
 
+    #[test]
+    fn feed_reader() {
+        let mut chain = Chain::new();
+        chain
+            .feed_reader(&b"I like  cats\nI hate dogs\n"[..])
+            .unwrap();
+        let mut fed = Chain::new();
+        fed.feed_str("I like cats").feed_str("I hate dogs");
+        assert_eq!(chain, fed);
+    }
+
     #[test]
     fn generate_str() {
         let mut chain = Chain::new();
@@ -568,6 +1788,32 @@ mod test {
         assert_eq!(chain, new_chain);
     }
 
+    #[test]
+    #[cfg(feature = "yaml")]
+    fn load_without_vocabulary() {
+        // Chains saved by older versions of this crate only have their transitions and order.
+        let saved = "map:
+  ? - ~
+  : I: 2
+  ? - I
+  : like: 2
+  ? - like
+  : cats: 1
+    dogs: 1
+  ? - cats
+  : ~: 1
+  ? - dogs
+  : ~: 1
+order: 1
+";
+        let chain: Chain<String> = super::yaml::from_str(saved).unwrap();
+        let mut fed = Chain::new();
+        fed.feed_str("I like cats").feed_str("I like dogs");
+        assert_eq!(chain, fed);
+        let resaved = super::yaml::to_string(&chain).unwrap();
+        assert!(resaved.contains("cats: 1"), "{}", resaved);
+    }
+
     #[test]
     fn merge() {
         let mut chain = Chain::of_order(2);
@@ -585,4 +1831,23 @@ mod test {
         new_chain.merge(another_chain);
         assert_eq!(chain, new_chain);
     }
+
+    #[test]
+    fn debug() {
+        let mut chain = Chain::new();
+        chain.feed_str("I like cats");
+        let debug = format!("{:?}", chain);
+        assert!(
+            debug.contains(r#"[Some("I")]: {Some("like"): 1}"#),
+            "{}",
+            debug
+        );
+        assert!(debug.contains(r#"[Some("cats")]: {None: 1}"#), "{}", debug);
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_many_tokens() {
+        to_id(UNKNOWN as usize);
+    }
 }
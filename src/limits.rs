@@ -0,0 +1,557 @@
+//! Generation of sequences whose length is constrained.
+
+use std::collections::HashMap;
+
+use rand::{thread_rng, Rng};
+
+use super::constrained::Transitions;
+use super::ends::Ends;
+use super::source::Source;
+use super::{Chain, Chainable, FrozenChain, Id, Token};
+
+/// What to do when generation runs into the limits on the length of a sequence.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LimitPolicy {
+    /// Cut the output off as soon as it reaches the maximum length.
+    #[default]
+    Truncate,
+    /// Generate up to the given number of sequences as usual, and return the first one that is
+    /// within the limits. Accepted sequences follow the distribution of the chain exactly, but
+    /// there may be none.
+    Retry(usize),
+    /// Weight each successor by the probability of ending the sequence within the limits from
+    /// the context it leads to, as far as the observed transitions of the chain tell, so that
+    /// sequences are drawn from the chain conditioned on ending within the limits. If no
+    /// sequence can end within them, only successors that fit are sampled and the output is
+    /// truncated.
+    Steer,
+}
+
+/// Limits on the length of generated sequences.
+///
+/// ```
+/// use markov::{Chain, LimitPolicy, Limits};
+///
+/// let mut chain = Chain::new();
+/// chain.feed_str("I like cats and I like dogs.");
+/// let limits = Limits {
+///     max_tokens: Some(5),
+///     max_chars: Some(20),
+///     on_limit: LimitPolicy::Steer,
+///     ..Limits::default()
+/// };
+/// let generated = chain.generate_str_with_limits(&limits).unwrap();
+/// assert!(generated.len() <= 20);
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Limits {
+    /// The minimum number of tokens in a sequence. Unless the policy is to retry, the end of a
+    /// sequence is only sampled before this many tokens when there is no other successor.
+    pub min_tokens: usize,
+    /// The maximum number of tokens in a sequence, if any.
+    pub max_tokens: Option<usize>,
+    /// The maximum number of characters in a generated string, including the spaces between its
+    /// tokens, if any. This is only taken into account by the string generation methods of
+    /// `Chain<String>`.
+    pub max_chars: Option<usize>,
+    /// What to do when generation runs into these limits.
+    pub on_limit: LimitPolicy,
+}
+
+impl Limits {
+    /// Determines whether one more token of the given width fits within these limits, after
+    /// `len` tokens of combined width `width`.
+    fn fits(&self, len: usize, width: usize, next: usize) -> bool {
+        self.max_tokens.map_or(true, |max| len < max)
+            && self
+                .max_chars
+                .map_or(true, |max| width + separator(len) + next <= max)
+    }
+}
+
+/// Gets the number of characters between the tokens of a sequence before the token following
+/// `len` tokens.
+fn separator(len: usize) -> usize {
+    if len == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// The probability of ending a sequence within some limits from the states reached while
+/// steering, computed from the observed transitions of a chain as they are needed.
+///
+/// A state is a context with the number of tokens and characters generated so far. These are
+/// only distinguished as far as the limits care, but every step still adds a token to a bounded
+/// count or a character to a bounded width, so no state can be reached again from itself and the
+/// probabilities are computed without cycles.
+struct Reach<'a, 'b, T: Chainable + 'a, W> {
+    transitions: Transitions<'a, T>,
+    limits: &'b Limits,
+    width: W,
+    memo: HashMap<(usize, usize, usize), f64>,
+}
+
+impl<'a, 'b, T, W> Reach<'a, 'b, T, W>
+where
+    T: Chainable + 'a,
+    W: Fn(&T) -> usize,
+{
+    /// Gets the key of a state, collapsing the counts that the limits do not bound. Without a
+    /// maximum number of tokens, the count is still kept apart from zero so that the separator
+    /// before the next token is known.
+    fn key(&self, context: usize, len: usize, used: usize) -> (usize, usize, usize) {
+        let len = match self.limits.max_tokens {
+            Some(_) => len,
+            None => len.min(self.limits.min_tokens.max(1)),
+        };
+        let used = match self.limits.max_chars {
+            Some(_) => used,
+            None => 0,
+        };
+        (context, len, used)
+    }
+
+    /// Gets the probability of ending within the limits after moving from the given context to
+    /// the given token of the given width, after `len` tokens of combined width `used`.
+    fn after(
+        &mut self,
+        context: &[Token<Id>],
+        next: Id,
+        width: usize,
+        len: usize,
+        used: usize,
+    ) -> f64 {
+        let mut target = context[1..].to_vec();
+        target.push(Some(next));
+        match self.transitions.find(&target) {
+            Some(target) => {
+                let key = self.key(target, len + 1, used + separator(len) + width);
+                self.value(key)
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Gets the probability of ending within the limits from the given state, computing it and
+    /// the probabilities it depends on without recursion.
+    fn value(&mut self, root: (usize, usize, usize)) -> f64 {
+        let mut stack = vec![root];
+        while let Some(&key) = stack.last() {
+            if self.memo.contains_key(&key) {
+                stack.pop();
+                continue;
+            }
+            let (context, len, used) = key;
+            let mut value = 0.0;
+            let mut pending = false;
+            for &(token, p, target) in &self.transitions.edges[context] {
+                match (token, target) {
+                    (None, _) if len >= self.limits.min_tokens => value += p,
+                    (Some(token), Some(target)) => {
+                        let width = (self.width)(token);
+                        if !self.limits.fits(len, used, width) {
+                            continue;
+                        }
+                        let next = self.key(target, len + 1, used + separator(len) + width);
+                        match self.memo.get(&next) {
+                            Some(&reach) => value += p * reach,
+                            None => {
+                                stack.push(next);
+                                pending = true;
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            if !pending {
+                self.memo.insert(key, value);
+                stack.pop();
+            }
+        }
+        self.memo[&root]
+    }
+}
+
+impl<'a, T> Source<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Generates a collection of tokens from the chain whose length is within the given limits,
+    /// leaving out the maximum number of characters.
+    fn generate_with_limits<R: Rng + ?Sized>(self, limits: &Limits, rng: &mut R) -> Option<Vec<T>> {
+        let limits = Limits {
+            max_chars: None,
+            ..*limits
+        };
+        self.generate_limited(&limits, |_| 0, rng)
+    }
+
+    /// Generates a collection of tokens within the given limits, where `width` gives the number of
+    /// characters each token takes up.
+    fn generate_limited<R, W>(self, limits: &Limits, width: W, rng: &mut R) -> Option<Vec<T>>
+    where
+        R: Rng + ?Sized,
+        W: Fn(&T) -> usize,
+    {
+        if let LimitPolicy::Retry(attempts) = limits.on_limit {
+            return (0..attempts).find_map(|_| self.generate_within(limits, &width, rng));
+        }
+
+        let steer = limits.on_limit == LimitPolicy::Steer;
+        // Without a maximum, every sequence that reaches the minimum ends within the limits.
+        let mut reach = match limits.max_tokens.or(limits.max_chars) {
+            Some(_) if steer => Some(Reach {
+                transitions: Transitions::new(self),
+                limits,
+                width: &width,
+                memo: HashMap::new(),
+            }),
+            _ => None,
+        };
+        let mut ends = Ends::new();
+        let mut curs = vec![None; self.order()];
+        let mut ret = Vec::new();
+        let mut used = 0;
+        while self.can_end(&curs, &mut ends) {
+            let fits = |token: &Token<Id>| match *token {
+                None => ret.len() >= limits.min_tokens,
+                Some(id) => !steer || limits.fits(ret.len(), used, width(self.token(id))),
+            };
+            let steered = reach.as_mut().and_then(|reach| {
+                let weight = |token: &Token<Id>| match *token {
+                    None if ret.len() >= limits.min_tokens => 1.0,
+                    Some(id) if fits(token) => {
+                        reach.after(&curs, id, width(self.token(id)), ret.len(), used)
+                    }
+                    _ => 0.0,
+                };
+                self.next_token_weighted(&curs, weight, rng)
+            });
+            let fitting = |token: &Token<Id>| if fits(token) { 1.0 } else { 0.0 };
+            let next = match steered.or_else(|| self.next_token_weighted(&curs, fitting, rng)) {
+                Some(next) => next,
+                // Either the sequence can only end before the minimum, or it cannot be steered
+                // to an end within the maximum.
+                None if steer => break,
+                None => self.next_token(&curs, rng),
+            };
+            match next {
+                Some(next) => {
+                    let token = self.token(next);
+                    if !limits.fits(ret.len(), used, width(token)) {
+                        break;
+                    }
+                    used += separator(ret.len()) + width(token);
+                    curs.remove(0);
+                    curs.push(Some(next));
+                    ret.push(token.clone());
+                }
+                None => break,
+            }
+        }
+        Some(ret)
+    }
+
+    /// Generates a collection of tokens as usual, giving up as soon as it exceeds the given limits.
+    fn generate_within<R, W>(self, limits: &Limits, width: W, rng: &mut R) -> Option<Vec<T>>
+    where
+        R: Rng + ?Sized,
+        W: Fn(&T) -> usize,
+    {
+        let mut ends = Ends::new();
+        let mut curs = vec![None; self.order()];
+        let mut ret = Vec::new();
+        let mut used = 0;
+        while self.can_end(&curs, &mut ends) {
+            let next = match self.next_token(&curs, rng) {
+                Some(next) => next,
+                None => break,
+            };
+            let token = self.token(next);
+            if !limits.fits(ret.len(), used, width(token)) {
+                return None;
+            }
+            used += separator(ret.len()) + width(token);
+            curs.remove(0);
+            curs.push(Some(next));
+            ret.push(token.clone());
+        }
+        if ret.len() < limits.min_tokens {
+            return None;
+        }
+        Some(ret)
+    }
+}
+
+impl<'a> Source<'a, String> {
+    /// Generates a random string of text whose length is within the given limits, including the
+    /// maximum number of characters.
+    fn generate_str_with_limits<R: Rng + ?Sized>(
+        self,
+        limits: &Limits,
+        rng: &mut R,
+    ) -> Option<String> {
+        self.generate_limited(limits, |s| s.chars().count(), rng)
+            .map(Chain::vec_to_string)
+    }
+}
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Generates a collection of tokens from the chain whose length is within the given limits.
+    /// This only returns `None` if the policy is to retry and no attempt was within the limits.
+    pub fn generate_with_limits(&self, limits: &Limits) -> Option<Vec<T>> {
+        self.generate_with_limits_and_rng(limits, &mut thread_rng())
+    }
+
+    /// Generates a collection of tokens from the chain whose length is within the given limits,
+    /// using the given random number generator. This only returns `None` if the policy is to
+    /// retry and no attempt was within the limits.
+    pub fn generate_with_limits_and_rng<R: Rng + ?Sized>(
+        &self,
+        limits: &Limits,
+        rng: &mut R,
+    ) -> Option<Vec<T>> {
+        Source::Chain(self).generate_with_limits(limits, rng)
+    }
+}
+
+impl Chain<String> {
+    /// Generates a random string of text whose length is within the given limits, including the
+    /// maximum number of characters. This only returns `None` if the policy is to retry and no
+    /// attempt was within the limits.
+    pub fn generate_str_with_limits(&self, limits: &Limits) -> Option<String> {
+        self.generate_str_with_limits_and_rng(limits, &mut thread_rng())
+    }
+
+    /// Generates a random string of text whose length is within the given limits, using the given
+    /// random number generator. This only returns `None` if the policy is to retry and no attempt
+    /// was within the limits.
+    pub fn generate_str_with_limits_and_rng<R: Rng + ?Sized>(
+        &self,
+        limits: &Limits,
+        rng: &mut R,
+    ) -> Option<String> {
+        Source::Chain(self).generate_str_with_limits(limits, rng)
+    }
+}
+
+impl<T> FrozenChain<T>
+where
+    T: Chainable,
+{
+    /// Generates a collection of tokens from the chain whose length is within the given limits,
+    /// as in `Chain::generate_with_limits`.
+    pub fn generate_with_limits(&self, limits: &Limits) -> Option<Vec<T>> {
+        self.generate_with_limits_and_rng(limits, &mut thread_rng())
+    }
+
+    /// Generates a collection of tokens from the chain whose length is within the given limits,
+    /// using the given random number generator.
+    pub fn generate_with_limits_and_rng<R: Rng + ?Sized>(
+        &self,
+        limits: &Limits,
+        rng: &mut R,
+    ) -> Option<Vec<T>> {
+        Source::Frozen(self).generate_with_limits(limits, rng)
+    }
+}
+
+impl FrozenChain<String> {
+    /// Generates a random string of text whose length is within the given limits, as in
+    /// `Chain::generate_str_with_limits`.
+    pub fn generate_str_with_limits(&self, limits: &Limits) -> Option<String> {
+        self.generate_str_with_limits_and_rng(limits, &mut thread_rng())
+    }
+
+    /// Generates a random string of text whose length is within the given limits, using the given
+    /// random number generator.
+    pub fn generate_str_with_limits_and_rng<R: Rng + ?Sized>(
+        &self,
+        limits: &Limits,
+        rng: &mut R,
+    ) -> Option<String> {
+        Source::Frozen(self).generate_str_with_limits(limits, rng)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Chain;
+    use super::{LimitPolicy, Limits};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn truncate() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![1u8, 2, 3])
+            .feed(vec![1, 1, 1, 1, 1, 1, 2])
+            .feed(vec![3]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let limits = Limits {
+            min_tokens: 2,
+            max_tokens: Some(3),
+            ..Limits::default()
+        };
+        for _ in 0..50 {
+            let v = chain
+                .generate_with_limits_and_rng(&limits, &mut rng)
+                .unwrap();
+            // Nothing but the end can follow a 3, so it can end early.
+            assert!(v.len() <= 3 && (v.len() >= 2 || v == [3]), "{:?}", v);
+        }
+    }
+
+    #[test]
+    fn retry() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![1u8, 2, 3])
+            .feed(vec![1, 1, 1, 1, 1, 1, 2])
+            .feed(vec![3]);
+        let mut rng = StdRng::seed_from_u64(2);
+        let limits = Limits {
+            min_tokens: 3,
+            max_tokens: Some(3),
+            on_limit: LimitPolicy::Retry(1000),
+            ..Limits::default()
+        };
+        for _ in 0..20 {
+            let v = chain.generate_with_limits_and_rng(&limits, &mut rng);
+            assert_eq!(v.unwrap().len(), 3);
+        }
+        let limits = Limits {
+            min_tokens: 100,
+            on_limit: LimitPolicy::Retry(10),
+            ..Limits::default()
+        };
+        assert_eq!(chain.generate_with_limits_and_rng(&limits, &mut rng), None);
+    }
+
+    #[test]
+    fn steer() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec![1u8, 2, 3])
+            .feed(vec![1, 1, 1, 1, 1, 1, 2])
+            .feed(vec![3]);
+        let mut rng = StdRng::seed_from_u64(3);
+        let limits = Limits {
+            max_tokens: Some(2),
+            on_limit: LimitPolicy::Steer,
+            ..Limits::default()
+        };
+        for _ in 0..50 {
+            let v = chain
+                .generate_with_limits_and_rng(&limits, &mut rng)
+                .unwrap();
+            // A 1 that is followed by another 1 cannot end within 2 tokens, so it is avoided.
+            assert!(
+                v.len() <= 2 && chain.log_likelihood(&v).is_finite(),
+                "{:?}",
+                v
+            );
+        }
+        let limits = Limits {
+            min_tokens: 3,
+            max_tokens: Some(4),
+            on_limit: LimitPolicy::Steer,
+            ..Limits::default()
+        };
+        for _ in 0..50 {
+            let v = chain
+                .generate_with_limits_and_rng(&limits, &mut rng)
+                .unwrap();
+            assert!(v.len() >= 3 && v.len() <= 4, "{:?}", v);
+            assert!(chain.log_likelihood(&v).is_finite(), "{:?}", v);
+        }
+        let frozen = chain.freeze().unwrap();
+        for seed in 0..20 {
+            assert_eq!(
+                frozen.generate_with_limits_and_rng(&limits, &mut StdRng::seed_from_u64(seed)),
+                chain.generate_with_limits_and_rng(&limits, &mut StdRng::seed_from_u64(seed))
+            );
+        }
+    }
+
+    #[test]
+    fn max_chars() {
+        let mut chain = Chain::new();
+        chain
+            .feed_str("a bb ccc dddd")
+            .feed_str("a dddd ccc bb a")
+            .feed_str("bb a");
+        let mut rng = StdRng::seed_from_u64(4);
+        for &on_limit in &[LimitPolicy::Truncate, LimitPolicy::Steer] {
+            let limits = Limits {
+                max_chars: Some(6),
+                on_limit,
+                ..Limits::default()
+            };
+            for _ in 0..50 {
+                let s = chain
+                    .generate_str_with_limits_and_rng(&limits, &mut rng)
+                    .unwrap();
+                assert!(s.len() <= 6, "{}", s);
+                if on_limit == LimitPolicy::Steer {
+                    assert!(chain.log_likelihood_str(&s).is_finite(), "{}", s);
+                }
+            }
+            let frozen = chain.freeze().unwrap();
+            for seed in 0..20 {
+                assert_eq!(
+                    frozen.generate_str_with_limits_and_rng(
+                        &limits,
+                        &mut StdRng::seed_from_u64(seed)
+                    ),
+                    chain.generate_str_with_limits_and_rng(
+                        &limits,
+                        &mut StdRng::seed_from_u64(seed)
+                    )
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn steer_counts_separators() {
+        let mut chain = Chain::new();
+        chain.feed_str("a b c d").feed_str("xxxxxx");
+        let mut rng = StdRng::seed_from_u64(5);
+        let limits = Limits {
+            max_chars: Some(6),
+            on_limit: LimitPolicy::Steer,
+            ..Limits::default()
+        };
+        // Every prefix of the first sequence fits, but it cannot end within six characters.
+        for _ in 0..200 {
+            let s = chain.generate_str_with_limits_and_rng(&limits, &mut rng);
+            assert_eq!(s.unwrap(), "xxxxxx");
+        }
+    }
+
+    #[test]
+    fn steer_empty_tokens() {
+        let mut chain = Chain::new();
+        chain.feed_str("a   b");
+        let mut rng = StdRng::seed_from_u64(6);
+        let limits = Limits {
+            max_chars: Some(6),
+            on_limit: LimitPolicy::Steer,
+            ..Limits::default()
+        };
+        for _ in 0..20 {
+            let s = chain
+                .generate_str_with_limits_and_rng(&limits, &mut rng)
+                .unwrap();
+            assert!(s.len() <= 6, "{:?}", s);
+        }
+    }
+}
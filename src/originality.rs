@@ -0,0 +1,486 @@
+//! Detection of generated sequences that copy their training data verbatim.
+
+use std::collections::HashMap;
+
+use rand::{thread_rng, Rng};
+
+use super::source::Source;
+use super::{Cache, Chain, Chainable, Exhausted, FrozenChain, UNKNOWN};
+
+/// Marks the boundary between two sequences in an `OverlapIndex`, so that no overlap spans it. No
+/// token is ever at this position, as it is the id of unknown tokens.
+const SEPARATOR: u32 = UNKNOWN;
+
+/// Limits on how much of a generated sequence may be copied verbatim from a single sequence fed
+/// into the chain.
+///
+/// ```
+/// use markov::{Chain, Originality};
+///
+/// let mut chain = Chain::of_order(2);
+/// chain.set_overlap_index(true);
+/// chain.feed_str("I like cats and I like dogs");
+/// let originality = Originality {
+///     max_overlap: Some(4),
+///     ..Originality::default()
+/// };
+/// assert!(!chain.is_original(&["I", "like", "cats", "and", "I"].map(String::from), &originality));
+/// assert!(chain.is_original(&["I", "like", "dogs"].map(String::from), &originality));
+/// ```
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Originality {
+    /// The maximum number of consecutive tokens that may be copied, if any.
+    pub max_overlap: Option<usize>,
+    /// The maximum fraction of the tokens of a sequence that may be copied consecutively, if any.
+    pub max_ratio: Option<f64>,
+}
+
+impl Originality {
+    /// Determines whether an overlap of the given length is acceptable in a sequence of `len`
+    /// tokens.
+    fn allows(&self, overlap: usize, len: usize) -> bool {
+        self.max_overlap.map_or(true, |max| overlap <= max)
+            && self
+                .max_ratio
+                .map_or(true, |max| overlap as f64 <= max * len as f64)
+    }
+}
+
+/// The sequences fed into a chain, with every token replaced by its position in the vocabulary of
+/// the chain.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct OverlapIndex {
+    sequences: Vec<u32>,
+    #[serde(skip, default = "Cache::default")]
+    suffixes: Cache<SuffixAutomaton>,
+}
+
+impl OverlapIndex {
+    /// Adds a sequence of vocabulary positions to the index, or appends them to the last sequence
+    /// if `continued` is set.
+    pub(crate) fn add<I: IntoIterator<Item = usize>>(&mut self, positions: I, continued: bool) {
+        if !continued && !self.sequences.is_empty() {
+            self.sequences.push(SEPARATOR);
+        }
+        self.sequences
+            .extend(positions.into_iter().map(|position| position as u32));
+        self.suffixes.clear();
+    }
+
+    /// Adds the sequences of another index, whose positions are translated by `position`.
+    pub(crate) fn merge<F: Fn(usize) -> usize>(&mut self, other: OverlapIndex, position: F) {
+        if other.sequences.is_empty() {
+            return;
+        }
+        for sequence in other.sequences.split(|&id| id == SEPARATOR) {
+            self.add(sequence.iter().map(|&id| position(id as usize)), false);
+        }
+    }
+
+    /// Gets the length of the longest run of consecutive positions shared with any indexed
+    /// sequence, where `None` matches nothing.
+    fn longest_overlap<I: IntoIterator<Item = Option<usize>>>(&self, positions: I) -> usize {
+        let suffixes = self
+            .suffixes
+            .get_or_init(|| SuffixAutomaton::new(&self.sequences));
+        let (mut state, mut len, mut longest) = (0, 0, 0);
+        for position in positions {
+            let id = match position {
+                Some(position) => position as u32,
+                None => {
+                    state = 0;
+                    len = 0;
+                    continue;
+                }
+            };
+            // Follow suffix links until the match can be extended by this token, if ever.
+            loop {
+                if let Some(&next) = suffixes.states[state].next.get(&id) {
+                    state = next;
+                    len += 1;
+                    break;
+                }
+                match suffixes.states[state].link {
+                    Some(link) => {
+                        state = link;
+                        len = suffixes.states[state].len;
+                    }
+                    None => {
+                        len = 0;
+                        break;
+                    }
+                }
+            }
+            longest = longest.max(len);
+        }
+        longest
+    }
+}
+
+/// A state of a `SuffixAutomaton`.
+struct SuffixState {
+    len: usize,
+    link: Option<usize>,
+    next: HashMap<u32, usize>,
+}
+
+/// A [suffix automaton](https://en.wikipedia.org/wiki/Suffix_automaton) of the indexed sequences,
+/// which finds the longest overlap with a sequence in time linear in its length.
+struct SuffixAutomaton {
+    states: Vec<SuffixState>,
+}
+
+impl SuffixAutomaton {
+    /// Builds the automaton of the given sequence in linear time.
+    fn new(sequence: &[u32]) -> SuffixAutomaton {
+        let mut states = vec![SuffixState {
+            len: 0,
+            link: None,
+            next: HashMap::new(),
+        }];
+        let mut last = 0;
+        for &id in sequence {
+            let current = states.len();
+            states.push(SuffixState {
+                len: states[last].len + 1,
+                link: Some(0),
+                next: HashMap::new(),
+            });
+            let mut p = Some(last);
+            while let Some(q) = p.filter(|&q| !states[q].next.contains_key(&id)) {
+                states[q].next.insert(id, current);
+                p = states[q].link;
+            }
+            if let Some(p) = p {
+                let q = states[p].next[&id];
+                if states[p].len + 1 == states[q].len {
+                    states[current].link = Some(q);
+                } else {
+                    let clone = states.len();
+                    states.push(SuffixState {
+                        len: states[p].len + 1,
+                        link: states[q].link,
+                        next: states[q].next.clone(),
+                    });
+                    let mut p = Some(p);
+                    while let Some(r) = p.filter(|&r| states[r].next.get(&id) == Some(&q)) {
+                        states[r].next.insert(id, clone);
+                        p = states[r].link;
+                    }
+                    states[q].link = Some(clone);
+                    states[current].link = Some(clone);
+                }
+            }
+            last = current;
+        }
+        SuffixAutomaton { states }
+    }
+}
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Sets whether the chain retains an index of the sequences fed into it, which is needed to
+    /// detect output that copies them verbatim. The index holds one integer per fed token and is
+    /// saved with the chain. Only sequences fed after it is enabled are indexed, and disabling it
+    /// discards it. The index is disabled by default.
+    pub fn set_overlap_index(&mut self, enabled: bool) -> &mut Chain<T> {
+        if !enabled {
+            self.overlaps = None;
+        } else if self.overlaps.is_none() {
+            self.overlaps = Some(OverlapIndex::default());
+        }
+        self
+    }
+
+    /// Determines whether the chain retains an index of the sequences fed into it.
+    pub fn has_overlap_index(&self) -> bool {
+        self.overlaps.is_some()
+    }
+
+    /// Gets the length of the longest run of consecutive tokens that the given sequence shares
+    /// with any single indexed sequence. This returns `None` if the chain retains no index.
+    pub fn longest_overlap(&self, tokens: &[T]) -> Option<usize> {
+        Source::Chain(self).longest_overlap(tokens)
+    }
+
+    /// Determines whether the given sequence copies no more of any indexed sequence than the given
+    /// limits allow.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn is_original(&self, tokens: &[T], originality: &Originality) -> bool {
+        Source::Chain(self).is_original(tokens, originality)
+    }
+
+    /// Generates collections of tokens until one is original within the given limits, giving up
+    /// after `max_attempts` collections.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn generate_original(
+        &self,
+        originality: &Originality,
+        max_attempts: usize,
+    ) -> Result<Vec<T>, Exhausted> {
+        self.generate_original_with_rng(originality, max_attempts, &mut thread_rng())
+    }
+
+    /// Generates collections of tokens until one is original within the given limits, giving up
+    /// after `max_attempts` collections, using the given random number generator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn generate_original_with_rng<R: Rng + ?Sized>(
+        &self,
+        originality: &Originality,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<Vec<T>, Exhausted> {
+        Source::Chain(self).generate_original(originality, max_attempts, rng)
+    }
+}
+
+impl Chain<String> {
+    /// Generates random strings of text until one is original within the given limits, giving up
+    /// after `max_attempts` strings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn generate_str_original(
+        &self,
+        originality: &Originality,
+        max_attempts: usize,
+    ) -> Result<String, Exhausted> {
+        self.generate_original(originality, max_attempts)
+            .map(Chain::vec_to_string)
+    }
+
+    /// Generates random strings of text until one is original within the given limits, giving up
+    /// after `max_attempts` strings, using the given random number generator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn generate_str_original_with_rng<R: Rng + ?Sized>(
+        &self,
+        originality: &Originality,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<String, Exhausted> {
+        self.generate_original_with_rng(originality, max_attempts, rng)
+            .map(Chain::vec_to_string)
+    }
+}
+
+impl<'a, T> Source<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Gets the length of the longest run of consecutive tokens that the given sequence shares
+    /// with any single indexed sequence, if the chain retains an index.
+    fn longest_overlap(self, tokens: &[T]) -> Option<usize> {
+        let overlaps = self.settings().overlaps.as_ref()?;
+        Some(overlaps.longest_overlap(tokens.iter().map(|token| self.position(token))))
+    }
+
+    /// Determines whether the given sequence copies no more of any indexed sequence than the given
+    /// limits allow.
+    fn is_original(self, tokens: &[T], originality: &Originality) -> bool {
+        let overlap = self
+            .longest_overlap(tokens)
+            .expect("the chain retains no overlap index");
+        originality.allows(overlap, tokens.len())
+    }
+
+    /// Generates collections of tokens until one is original within the given limits.
+    fn generate_original<R: Rng + ?Sized>(
+        self,
+        originality: &Originality,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<Vec<T>, Exhausted> {
+        assert!(self.settings().has_overlap_index());
+        self.generate_where(
+            |tokens| self.is_original(tokens, originality),
+            max_attempts,
+            rng,
+        )
+    }
+}
+
+impl<T> FrozenChain<T>
+where
+    T: Chainable,
+{
+    /// Determines whether the chain retains an index of the sequences fed into it, which it keeps
+    /// from the chain it was built from.
+    pub fn has_overlap_index(&self) -> bool {
+        self.settings().has_overlap_index()
+    }
+
+    /// Gets the length of the longest run of consecutive tokens that the given sequence shares
+    /// with any single indexed sequence, as in `Chain::longest_overlap`. This returns `None` if
+    /// the chain retains no index.
+    pub fn longest_overlap(&self, tokens: &[T]) -> Option<usize> {
+        Source::Frozen(self).longest_overlap(tokens)
+    }
+
+    /// Determines whether the given sequence copies no more of any indexed sequence than the given
+    /// limits allow, as in `Chain::is_original`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn is_original(&self, tokens: &[T], originality: &Originality) -> bool {
+        Source::Frozen(self).is_original(tokens, originality)
+    }
+
+    /// Generates collections of tokens until one is original within the given limits, giving up
+    /// after `max_attempts` collections, as in `Chain::generate_original`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn generate_original(
+        &self,
+        originality: &Originality,
+        max_attempts: usize,
+    ) -> Result<Vec<T>, Exhausted> {
+        self.generate_original_with_rng(originality, max_attempts, &mut thread_rng())
+    }
+
+    /// Generates collections of tokens until one is original within the given limits, giving up
+    /// after `max_attempts` collections, using the given random number generator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn generate_original_with_rng<R: Rng + ?Sized>(
+        &self,
+        originality: &Originality,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<Vec<T>, Exhausted> {
+        Source::Frozen(self).generate_original(originality, max_attempts, rng)
+    }
+}
+
+impl FrozenChain<String> {
+    /// Generates random strings of text until one is original within the given limits, giving up
+    /// after `max_attempts` strings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn generate_str_original(
+        &self,
+        originality: &Originality,
+        max_attempts: usize,
+    ) -> Result<String, Exhausted> {
+        self.generate_original(originality, max_attempts)
+            .map(Chain::vec_to_string)
+    }
+
+    /// Generates random strings of text until one is original within the given limits, giving up
+    /// after `max_attempts` strings, using the given random number generator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain retains no index of the sequences fed into it.
+    pub fn generate_str_original_with_rng<R: Rng + ?Sized>(
+        &self,
+        originality: &Originality,
+        max_attempts: usize,
+        rng: &mut R,
+    ) -> Result<String, Exhausted> {
+        self.generate_original_with_rng(originality, max_attempts, rng)
+            .map(Chain::vec_to_string)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Chain;
+    use super::Originality;
+
+    #[test]
+    fn longest_overlap() {
+        let mut chain = Chain::new();
+        chain.feed(vec![1u8, 2, 3, 4]);
+        assert_eq!(chain.longest_overlap(&[1, 2, 3]), None);
+        chain.set_overlap_index(true);
+        chain.feed(vec![1u8, 2, 3, 4, 5]).feed(vec![2, 3, 1, 2, 6]);
+        assert_eq!(chain.longest_overlap(&[1, 2, 3, 4, 5]), Some(5));
+        assert_eq!(chain.longest_overlap(&[3, 1, 2, 3, 4]), Some(4));
+        // Overlaps never span two fed sequences.
+        assert_eq!(chain.longest_overlap(&[4, 5, 2, 3]), Some(2));
+        assert_eq!(chain.longest_overlap(&[2, 9, 2, 6, 1]), Some(2));
+        assert_eq!(chain.longest_overlap(&[]), Some(0));
+        let frozen = chain.freeze().unwrap();
+        assert_eq!(frozen.longest_overlap(&[3, 1, 2, 3, 4]), Some(4));
+        assert_eq!(frozen.longest_overlap(&[2, 9, 2, 6, 1]), Some(2));
+        chain.set_overlap_index(false);
+        assert_eq!(chain.longest_overlap(&[1, 2]), None);
+    }
+
+    #[test]
+    fn is_original() {
+        let mut chain = Chain::new();
+        chain.set_overlap_index(true);
+        chain.feed(vec![1u8, 2, 3, 4, 5, 6]);
+        let originality = Originality {
+            max_overlap: Some(3),
+            max_ratio: Some(0.5),
+        };
+        assert!(chain.is_original(&[1, 2, 3, 9, 9, 9], &originality));
+        assert!(!chain.is_original(&[1, 2, 3, 4, 9, 9, 9, 9], &originality));
+        assert!(!chain.is_original(&[1, 2, 3, 9], &originality));
+        assert!(chain.is_original(&[1, 2, 3], &Originality::default()));
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = Chain::new();
+        a.set_overlap_index(true);
+        a.feed(vec![1u8, 2, 3]);
+        let mut b = Chain::new();
+        b.set_overlap_index(true);
+        b.feed(vec![4u8, 5, 1, 6]);
+        a.merge(b);
+        assert_eq!(a.longest_overlap(&[4, 5, 1, 6]), Some(4));
+        assert_eq!(a.longest_overlap(&[5, 1, 2]), Some(2));
+    }
+
+    #[test]
+    fn generate_original() {
+        let mut chain = Chain::of_order(2);
+        chain.set_overlap_index(true);
+        chain
+            .feed_str("I like cats and I like dogs")
+            .feed_str("you like cats and you like birds");
+        let originality = Originality {
+            max_overlap: Some(4),
+            ..Originality::default()
+        };
+        for _ in 0..20 {
+            let s = chain.generate_str_original(&originality, 1000).unwrap();
+            assert!(s != "I like cats and I like dogs", "{}", s);
+            assert!(s != "you like cats and you like birds", "{}", s);
+        }
+        let originality = Originality {
+            max_overlap: Some(2),
+            ..Originality::default()
+        };
+        assert!(chain.generate_original(&originality, 10).is_err());
+        let frozen = chain.freeze().unwrap();
+        assert!(frozen.has_overlap_index());
+        assert!(frozen.generate_original(&originality, 10).is_err());
+        assert_eq!(frozen.thaw(), chain);
+    }
+}
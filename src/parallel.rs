@@ -0,0 +1,131 @@
+//! Training of chains on many threads at once, with the `rayon` feature.
+
+use std::io::Result;
+use std::path::Path;
+
+use rayon::iter::{
+    FromParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+
+use super::{Chain, Chainable};
+
+impl<T> Chain<T>
+where
+    T: Chainable + Send,
+{
+    /// Creates an empty chain of the given order, that indexes its sequences if asked to, so that
+    /// it can be merged into a chain that does.
+    fn empty(order: usize, indexed: bool) -> Chain<T> {
+        let mut chain = Chain::of_order(order);
+        chain.set_overlap_index(indexed);
+        chain
+    }
+
+    /// Feeds the chain every collection of tokens of a parallel iterator, using all available
+    /// threads. Every thread builds a chain of its own, and these are merged in order, so the
+    /// result is exactly the same as feeding every collection in turn.
+    pub fn par_feed_all<I>(&mut self, sequences: I) -> &mut Chain<T>
+    where
+        I: IntoParallelIterator,
+        I::Item: AsRef<[T]>,
+    {
+        let (order, indexed) = (self.order, self.has_overlap_index());
+        let empty = || Chain::empty(order, indexed);
+        let chain = sequences
+            .into_par_iter()
+            .fold(empty, |mut chain, tokens| {
+                chain.feed(tokens);
+                chain
+            })
+            .reduce(empty, |mut chain, other| {
+                chain.merge(other);
+                chain
+            });
+        self.merge(chain);
+        self
+    }
+}
+
+impl<T, S> FromParallelIterator<S> for Chain<T>
+where
+    T: Chainable + Send,
+    S: AsRef<[T]> + Send,
+{
+    /// Creates a new Markov chain of order 1 fed with every collection of tokens of the parallel
+    /// iterator, as in `par_feed_all`.
+    fn from_par_iter<I: IntoParallelIterator<Item = S>>(par_iter: I) -> Chain<T> {
+        let mut chain = Chain::new();
+        chain.par_feed_all(par_iter);
+        chain
+    }
+}
+
+impl Chain<String> {
+    /// Feeds the given files into the chain as in `feed_file`, reading and processing them on all
+    /// available threads. The result is exactly the same as feeding every file in turn.
+    pub fn feed_files<P: AsRef<Path> + Sync>(&mut self, paths: &[P]) -> Result<&mut Chain<String>> {
+        let (order, indexed) = (self.order, self.has_overlap_index());
+        let empty = || Ok(Chain::empty(order, indexed));
+        let chain = paths
+            .par_iter()
+            .map(|path| {
+                let mut chain = Chain::empty(order, indexed);
+                chain.feed_file(path)?;
+                Ok(chain)
+            })
+            .reduce(empty, |chain: Result<Chain<String>>, other| {
+                let mut chain = chain?;
+                chain.merge(other?);
+                Ok(chain)
+            })?;
+        self.merge(chain);
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Chain;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+    #[test]
+    fn par_feed_all() {
+        let sequences: Vec<Vec<u16>> = (0..2000u16)
+            .map(|i| (0..i % 7).map(|j| (i * j) % 13).collect())
+            .collect();
+        let mut chain = Chain::of_order(2);
+        chain.par_feed_all(&sequences);
+        let mut fed = Chain::of_order(2);
+        for tokens in &sequences {
+            fed.feed(tokens);
+        }
+        assert_eq!(chain, fed);
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut fed_rng = StdRng::seed_from_u64(seed);
+            assert_eq!(
+                chain.generate_with_rng(&mut rng),
+                fed.generate_with_rng(&mut fed_rng)
+            );
+        }
+
+        let collected: Chain<u16> = sequences.into_par_iter().collect();
+        assert_eq!(collected.order(), 1);
+        assert!(!collected.is_empty());
+    }
+
+    #[test]
+    fn feed_files() {
+        let mut chain = Chain::new();
+        chain.feed_files(&["test", "jabberwocky"]).unwrap();
+        let mut fed = Chain::new();
+        fed.feed_file("test")
+            .unwrap()
+            .feed_file("jabberwocky")
+            .unwrap();
+        assert_eq!(chain, fed);
+        assert!(Chain::new().feed_files(&["test", "missing"]).is_err());
+    }
+}
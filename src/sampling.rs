@@ -0,0 +1,291 @@
+//! Reshaping of the successor distribution before each token is sampled.
+
+use rand::Rng;
+
+use super::{choose, Chain, Chainable, Token};
+
+/// The settings that reshape the distribution of successors of a context before sampling.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Sampling {
+    temperature: f64,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+        }
+    }
+}
+
+impl Sampling {
+    /// Checks that every setting is in its allowed range.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.temperature >= 0.0
+            && self.temperature.is_finite()
+            && self.top_k != Some(0)
+            && self.top_p.map_or(true, |p| p > 0.0 && p <= 1.0)
+    }
+
+    /// Determines whether these settings leave the distribution of successors unchanged.
+    pub(crate) fn is_plain(&self) -> bool {
+        self.temperature == 1.0 && self.top_k.is_none() && self.top_p.is_none()
+    }
+
+    /// Determines whether every successor with a positive weight may still be sampled under
+    /// these settings.
+    pub(crate) fn keeps_support(&self) -> bool {
+        self.temperature > 0.0 && self.top_k.is_none() && self.top_p.is_none()
+    }
+
+    /// Reshapes the given weights according to these settings.
+    fn reshape(&self, weights: &mut [f64]) {
+        let max = weights.iter().cloned().fold(0.0, f64::max);
+        if max <= 0.0 {
+            return;
+        }
+        for weight in weights.iter_mut() {
+            // Scaling by the largest weight first keeps sharp temperatures from overflowing.
+            *weight = if self.temperature == 0.0 {
+                if *weight == max {
+                    1.0
+                } else {
+                    0.0
+                }
+            } else {
+                (*weight / max).powf(1.0 / self.temperature)
+            };
+        }
+        if self.top_k.is_none() && self.top_p.is_none() {
+            return;
+        }
+
+        // Rank the weights from most to least likely, breaking ties by position so that the
+        // truncation is reproducible.
+        let mut ranked: Vec<_> = (0..weights.len()).collect();
+        ranked.sort_by(|&a, &b| weights[b].total_cmp(&weights[a]));
+        let mut keep = self.top_k.unwrap_or(weights.len()).min(weights.len());
+        if let Some(top_p) = self.top_p {
+            let sum: f64 = weights.iter().sum();
+            let mut acc = 0.0;
+            for (n, &i) in ranked[..keep].iter().enumerate() {
+                acc += weights[i];
+                if acc >= top_p * sum {
+                    keep = n + 1;
+                    break;
+                }
+            }
+        }
+        for &i in &ranked[keep..] {
+            weights[i] = 0.0;
+        }
+    }
+
+    /// Samples one of the given successors, with probability proportional to its reshaped weight.
+    /// This returns `None` if no successor has a positive weight.
+    pub(crate) fn next<T, R>(
+        &self,
+        successors: Vec<(&Token<T>, f64)>,
+        rng: &mut R,
+    ) -> Option<Token<T>>
+    where
+        T: Chainable,
+        R: Rng + ?Sized,
+    {
+        let weights = successors.iter().map(|&(_, weight)| weight).collect();
+        self.sample(weights, rng).map(|i| successors[i].0.clone())
+    }
+
+    /// Gets the indices of the given weights that remain positive once reshaped, i.e. those that
+    /// may be sampled.
+    pub(crate) fn support(&self, mut weights: Vec<f64>) -> Vec<usize> {
+        self.reshape(&mut weights);
+        (0..weights.len()).filter(|&i| weights[i] > 0.0).collect()
+    }
+
+    /// Samples the index of one of the given weights, with probability proportional to its
+    /// reshaped value. This returns `None` if no weight is positive.
+    pub(crate) fn sample<R: Rng + ?Sized>(
+        &self,
+        mut weights: Vec<f64>,
+        rng: &mut R,
+    ) -> Option<usize> {
+        self.reshape(&mut weights);
+        choose(&weights, rng)
+    }
+}
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Sets the temperature used when generating from the chain. The probability of every
+    /// successor is raised to the power of `1 / temperature` before sampling, so temperatures
+    /// below `1.0` sharpen the distribution (output more often repeats the training data) and
+    /// temperatures above `1.0` flatten it (output is more varied). A temperature of `0.0` always
+    /// picks the most likely successor, choosing at random between ties. The default is `1.0`,
+    /// which samples in proportion to the counts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the temperature is negative or not finite.
+    pub fn set_temperature(&mut self, temperature: f64) -> &mut Chain<T> {
+        let sampling = Sampling {
+            temperature,
+            ..self.sampling
+        };
+        assert!(sampling.is_valid());
+        self.sampling = sampling;
+        self.endless.clear();
+        self
+    }
+
+    /// Gets the temperature used when generating from the chain.
+    pub fn temperature(&self) -> f64 {
+        self.sampling.temperature
+    }
+
+    /// Restricts generation to the `k` most likely successors of each context, which cuts off the
+    /// long tail of rarely seen successors. Ties are broken in favor of the successor that was
+    /// fed into the chain first. `None`, the default, disables the restriction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `k` is `Some(0)`.
+    pub fn set_top_k(&mut self, k: Option<usize>) -> &mut Chain<T> {
+        let sampling = Sampling {
+            top_k: k,
+            ..self.sampling
+        };
+        assert!(sampling.is_valid());
+        self.sampling = sampling;
+        self.endless.clear();
+        self
+    }
+
+    /// Gets the number of most likely successors that generation is restricted to, if any.
+    pub fn top_k(&self) -> Option<usize> {
+        self.sampling.top_k
+    }
+
+    /// Restricts generation to the smallest set of most likely successors of each context whose
+    /// combined probability is at least `p`, also known as nucleus sampling. `None`, the default,
+    /// disables the restriction. This is applied after the temperature and `top_k`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `p` is not greater than `0.0` and at most `1.0`.
+    pub fn set_top_p(&mut self, p: Option<f64>) -> &mut Chain<T> {
+        let sampling = Sampling {
+            top_p: p,
+            ..self.sampling
+        };
+        assert!(sampling.is_valid());
+        self.sampling = sampling;
+        self.endless.clear();
+        self
+    }
+
+    /// Gets the combined probability of the most likely successors that generation is restricted
+    /// to, if any.
+    pub fn top_p(&self) -> Option<f64> {
+        self.sampling.top_p
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Chain;
+    use super::Sampling;
+
+    #[test]
+    fn reshape() {
+        let sampling = |temperature, top_k, top_p| Sampling {
+            temperature,
+            top_k,
+            top_p,
+        };
+        let mut weights = [1.0, 2.0, 4.0];
+        sampling(0.5, None, None).reshape(&mut weights);
+        assert_eq!(weights, [1.0 / 16.0, 1.0 / 4.0, 1.0]);
+        let mut weights = [1.0, 4.0, 4.0];
+        sampling(0.0, None, None).reshape(&mut weights);
+        assert_eq!(weights, [0.0, 1.0, 1.0]);
+        let mut weights = [1.0, 4.0, 2.0, 4.0];
+        sampling(1.0, Some(2), None).reshape(&mut weights);
+        assert_eq!(weights, [0.0, 1.0, 0.0, 1.0]);
+        let mut weights = [1.0, 5.0, 2.0, 2.0];
+        sampling(1.0, None, Some(0.7)).reshape(&mut weights);
+        assert_eq!(weights, [0.0, 1.0, 0.4, 0.0]);
+        let mut weights = [1.0, 5.0, 2.0, 2.0];
+        sampling(1.0, Some(1), Some(0.9)).reshape(&mut weights);
+        assert_eq!(weights, [0.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn zero_temperature() {
+        let mut chain = Chain::new();
+        chain
+            .feed_str("I like cats")
+            .feed_str("I like cats")
+            .feed_str("I hate dogs")
+            .set_temperature(0.0);
+        for _ in 0..20 {
+            assert_eq!(chain.generate_str(), "I like cats");
+            assert_eq!(chain.generate_str_from_token("like"), "like cats");
+        }
+        assert!(chain.str_iter_for(5).all(|s| s == "I like cats"));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut chain = Chain::new();
+        chain
+            .feed_str("I like cats")
+            .feed_str("I like cats")
+            .feed_str("I like cats")
+            .feed_str("I like dogs")
+            .feed_str("I like birds");
+        chain.set_top_k(Some(1));
+        assert!(chain.str_iter_for(20).all(|s| s == "I like cats"));
+        chain.set_top_k(None).set_top_p(Some(0.6));
+        assert!(chain.str_iter_for(20).all(|s| s == "I like cats"));
+        chain.set_top_p(Some(0.8));
+        let allowed = ["I like cats", "I like dogs"];
+        assert!(chain.str_iter_for(50).all(|s| allowed.contains(&&s[..])));
+    }
+
+    #[test]
+    fn high_temperature() {
+        let mut chain = Chain::new();
+        for _ in 0..99 {
+            chain.feed(vec![1u8]);
+        }
+        chain.feed(vec![2u8]).set_temperature(1e6);
+        // The start state has two successors which are now almost equally likely.
+        let twos = chain.iter_for(200).filter(|v| v[0] == 2).count();
+        assert!(twos > 50, "{}", twos);
+    }
+
+    #[test]
+    #[should_panic]
+    fn top_p_needs_positive_mass() {
+        Chain::<u8>::new().set_top_p(Some(0.0));
+    }
+
+    #[test]
+    #[cfg(feature = "yaml")]
+    fn load_invalid_sampling() {
+        for settings in &["temperature: -1.0", "top_k: 0", "top_p: 1.5"] {
+            let saved = format!("map: {{}}\norder: 1\nsampling:\n  {}\n", settings);
+            assert!(super::super::yaml::from_str::<Chain<String>>(&saved).is_err());
+        }
+        let saved = "map: {}\norder: 1\nsampling:\n  top_k: 2\n";
+        assert!(super::super::yaml::from_str::<Chain<String>>(saved).is_ok());
+    }
+}
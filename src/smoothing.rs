@@ -0,0 +1,475 @@
+//! Smoothing and backoff of transition probabilities, so that transitions which were never fed
+//! into a chain are not considered impossible.
+
+use std::collections::HashMap;
+
+use super::{Chain, Chainable, Id, States, Token};
+
+/// A strategy for assigning probability to transitions that were never seen. Smoothing only
+/// changes how probabilities are computed from the counts of a chain; the counts themselves are
+/// never modified.
+///
+/// Every strategy reserves a share of probability for tokens that were never fed into the chain,
+/// so that held-out data containing new tokens has a finite perplexity.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum Smoothing {
+    /// No smoothing: probabilities are the raw, maximum-likelihood estimates from the counts.
+    #[default]
+    None,
+    /// [Additive smoothing](https://en.wikipedia.org/wiki/Additive_smoothing), which adds the
+    /// given pseudo-count to every possible transition, which must be positive. A pseudo-count of
+    /// `1.0` is Laplace smoothing.
+    Additive(f64),
+    /// Witten-Bell smoothing, which interpolates with lower orders according to how many distinct
+    /// successors each context has.
+    WittenBell,
+    /// Interpolated [Kneser-Ney smoothing](https://en.wikipedia.org/wiki/Kneser%E2%80%93Ney_smoothing)
+    /// with the given absolute discount, which must be strictly between `0.0` and `1.0`. A
+    /// discount of `0.75` is a common choice.
+    KneserNey(f64),
+}
+
+impl Smoothing {
+    /// Checks that the parameter of the strategy, if any, is in its allowed range.
+    pub(crate) fn is_valid(self) -> bool {
+        match self {
+            Smoothing::Additive(delta) => delta > 0.0 && delta.is_finite(),
+            Smoothing::KneserNey(discount) => discount > 0.0 && discount < 1.0,
+            Smoothing::None | Smoothing::WittenBell => true,
+        }
+    }
+}
+
+/// A strategy for falling back to shorter contexts when a context, or a transition out of it, was
+/// never seen. The counts of every order below that of the chain are derived from its own counts.
+///
+/// During generation, a context that was never seen is replaced by its longest suffix that was,
+/// whatever the strategy. When scoring, the strategy determines how probability is assigned to
+/// transitions that were never seen. A smoothing strategy, if any, takes precedence when scoring.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum Backoff {
+    /// No backoff: contexts that were never seen cannot be continued.
+    #[default]
+    None,
+    /// [Stupid backoff](https://aclanthology.org/D07-1090.pdf), which multiplies the score of a
+    /// shorter context by the given positive factor (commonly `0.4`) for every token dropped from
+    /// the context. Scores are cheap to compute but are not normalized, so cross-entropy and
+    /// perplexity are only comparable between chains using stupid backoff.
+    Stupid(f64),
+    /// [Katz-style backoff](https://en.wikipedia.org/wiki/Katz%27s_back-off_model), which takes
+    /// the given absolute discount (strictly between `0.0` and `1.0`) from every seen transition and
+    /// distributes it over unseen ones according to the shorter context.
+    Katz(f64),
+}
+
+impl Backoff {
+    /// Checks that the parameter of the strategy, if any, is in its allowed range.
+    pub(crate) fn is_valid(self) -> bool {
+        match self {
+            Backoff::Stupid(factor) => factor > 0.0 && factor.is_finite(),
+            Backoff::Katz(discount) => discount > 0.0 && discount < 1.0,
+            Backoff::None => true,
+        }
+    }
+}
+
+/// Statistics for every order below that of a chain, derived from its counts. These are computed
+/// when first needed and discarded whenever the chain is fed.
+#[derive(Debug)]
+pub(crate) struct Levels {
+    /// The id of every token of the chain, including the end of sequence marker, in the order it
+    /// was first fed into the chain.
+    vocabulary: Vec<Token<Id>>,
+    /// The summed counts of the successors of every context, indexed by the length of the context.
+    counts: Vec<HashMap<Vec<Token<Id>>, States<Id>>>,
+    /// The number of distinct one token longer contexts in which each successor of a context was
+    /// seen, indexed by the length of the context.
+    continuations: Vec<HashMap<Vec<Token<Id>>, States<Id>>>,
+}
+
+impl Levels {
+    /// Derives the lower-order statistics of the given chain.
+    fn new<T: Chainable>(chain: &Chain<T>) -> Levels {
+        let vocabulary: Vec<_> = chain
+            .vocabulary
+            .iter()
+            .enumerate()
+            .map(|(position, (token, _))| token.as_ref().map(|_| position as Id))
+            .collect();
+        // Ids are positions in the vocabulary, except for the end of sequence marker.
+        let position = |token: &Token<Id>| match *token {
+            Some(id) => id as usize,
+            None => chain.vocabulary.position(&None).unwrap(),
+        };
+
+        let mut counts = Vec::with_capacity(chain.order);
+        let mut continuations = Vec::with_capacity(chain.order);
+        for _ in 0..chain.order {
+            let upper = counts.last().unwrap_or(&chain.map);
+            let mut level: HashMap<Vec<Token<Id>>, States<Id>> = HashMap::new();
+            let mut continuation: HashMap<Vec<Token<Id>>, States<Id>> = HashMap::new();
+            for (context, states) in upper {
+                for (token, count) in states.iter() {
+                    level
+                        .entry(context[1..].to_vec())
+                        .or_default()
+                        .add(*token, *count);
+                    continuation
+                        .entry(context[1..].to_vec())
+                        .or_default()
+                        .add(*token, 1);
+                }
+            }
+            for states in level.values_mut().chain(continuation.values_mut()) {
+                states.sort_by_key(&position);
+            }
+            counts.push(level);
+            continuations.push(continuation);
+        }
+        counts.reverse();
+        continuations.reverse();
+
+        Levels {
+            vocabulary,
+            counts,
+            continuations,
+        }
+    }
+}
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Sets the smoothing strategy used when computing probabilities. By default, no smoothing is
+    /// done.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pseudo-count of additive smoothing is not positive, or if the discount of
+    /// Kneser-Ney smoothing is not strictly between `0.0` and `1.0`.
+    pub fn set_smoothing(&mut self, smoothing: Smoothing) -> &mut Chain<T> {
+        assert!(smoothing.is_valid());
+        self.smoothing = smoothing;
+        self
+    }
+
+    /// Gets the smoothing strategy used when computing probabilities.
+    pub fn smoothing(&self) -> Smoothing {
+        self.smoothing
+    }
+
+    /// Sets whether generation samples from the smoothed distribution instead of the raw counts,
+    /// which allows it to produce transitions that were never seen. This has no effect without a
+    /// smoothing strategy, and is disabled by default.
+    pub fn set_smoothed_sampling(&mut self, enabled: bool) -> &mut Chain<T> {
+        self.smoothed_sampling = enabled;
+        self
+    }
+
+    /// Sets the backoff strategy used for contexts that were never seen. By default, there is no
+    /// backoff.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the factor of stupid backoff is not positive, or if the discount of Katz-style
+    /// backoff is not strictly between `0.0` and `1.0`.
+    pub fn set_backoff(&mut self, backoff: Backoff) -> &mut Chain<T> {
+        assert!(backoff.is_valid());
+        self.backoff = backoff;
+        self.endless.clear();
+        self
+    }
+
+    /// Gets the backoff strategy used for contexts that were never seen.
+    pub fn backoff(&self) -> Backoff {
+        self.backoff
+    }
+
+    /// Gets the lower-order statistics of the chain, deriving them if necessary.
+    pub(crate) fn levels(&self) -> &Levels {
+        self.levels.get_or_init(|| Levels::new(self))
+    }
+
+    /// Gets the counts of the successors of a context of any length up to the order of the chain.
+    pub(crate) fn counts(&self, context: &[Token<Id>]) -> Option<&States<Id>> {
+        if context.len() == self.order {
+            self.map.get(context)
+        } else {
+            self.levels().counts[context.len()].get(context)
+        }
+    }
+
+    /// Computes the smoothed probability that `next` follows the given context.
+    pub(crate) fn smoothed_probability(&self, context: &[Token<Id>], next: &Token<Id>) -> f64 {
+        let uniform = || 1.0 / (self.levels().vocabulary.len() + 1) as f64;
+        match self.smoothing {
+            Smoothing::None => match self.backoff {
+                Backoff::None => match self.map.get(context) {
+                    Some(states) if !states.is_empty() => {
+                        states.count(next) as f64 / states.sum() as f64
+                    }
+                    _ => 0.0,
+                },
+                Backoff::Stupid(factor) => {
+                    let mut scale = 1.0;
+                    for len in (0..=self.order).rev() {
+                        if let Some(states) = self.counts(&context[self.order - len..]) {
+                            let count = states.count(next);
+                            if count > 0 {
+                                return scale * count as f64 / states.sum() as f64;
+                            }
+                        }
+                        scale *= factor;
+                    }
+                    scale * uniform()
+                }
+                Backoff::Katz(discount) => self.katz_probability(context, next, discount),
+            },
+            Smoothing::Additive(delta) => {
+                let (count, sum) = self
+                    .map
+                    .get(context)
+                    .map_or((0, 0), |states| (states.count(next), states.sum()));
+                (count as f64 + delta) / (sum as f64 + delta / uniform())
+            }
+            Smoothing::WittenBell => (0..=self.order).fold(uniform(), |lower, len| {
+                match self.counts(&context[self.order - len..]) {
+                    Some(states) if !states.is_empty() => {
+                        let types = states.len() as f64;
+                        (states.count(next) as f64 + types * lower) / (states.sum() as f64 + types)
+                    }
+                    _ => lower,
+                }
+            }),
+            Smoothing::KneserNey(discount) => (0..=self.order).fold(uniform(), |lower, len| {
+                let context = &context[self.order - len..];
+                let states = if len == self.order {
+                    self.map.get(context)
+                } else {
+                    self.levels().continuations[len].get(context)
+                };
+                match states {
+                    Some(states) if !states.is_empty() => {
+                        let sum = states.sum() as f64;
+                        let count = states.count(next) as f64;
+                        (count - discount).max(0.0) / sum
+                            + discount * states.len() as f64 / sum * lower
+                    }
+                    _ => lower,
+                }
+            }),
+        }
+    }
+
+    /// Computes the probability that `next` follows the given context under Katz-style backoff.
+    fn katz_probability(&self, context: &[Token<Id>], next: &Token<Id>, discount: f64) -> f64 {
+        let uniform = 1.0 / (self.levels().vocabulary.len() + 1) as f64;
+        (0..=self.order).fold(uniform, |lower, len| {
+            let states = match self.counts(&context[self.order - len..]) {
+                Some(states) if !states.is_empty() => states,
+                _ => return lower,
+            };
+            let sum = states.sum() as f64;
+            let count = states.count(next);
+            if count > 0 {
+                return (count as f64 - discount) / sum;
+            }
+            // Every successor of a context is also a successor of its suffix, so the mass of the
+            // shorter context that is already accounted for can be read directly from its counts.
+            let seen = if len == 0 {
+                states.len() as f64 * uniform
+            } else {
+                let shorter = self.counts(&context[self.order - len + 1..]).unwrap();
+                let shorter_sum = shorter.sum() as f64;
+                states
+                    .iter()
+                    .map(|(token, _)| (shorter.count(token) as f64 - discount) / shorter_sum)
+                    .sum()
+            };
+            discount * states.len() as f64 / sum * lower / (1.0 - seen)
+        })
+    }
+
+    /// Gets the counts of the longest suffix of the given context that was seen.
+    pub(crate) fn backed_off_counts(&self, context: &[Token<Id>]) -> Option<&States<Id>> {
+        (1..=context.len())
+            .filter_map(|start| self.counts(&context[start..]))
+            .find(|states| !states.is_empty())
+    }
+
+    /// Gets the smoothed probability of every token of the chain following the given context.
+    pub(crate) fn smoothed_successors(&self, context: &[Token<Id>]) -> Vec<(&Token<Id>, f64)> {
+        self.levels()
+            .vocabulary
+            .iter()
+            .map(|token| (token, self.smoothed_probability(context, token)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Chain;
+    use super::{Backoff, Smoothing};
+
+    fn total(chain: &Chain<String>, context: &[Option<String>]) -> f64 {
+        chain.successors(context).iter().map(|&(_, p)| p).sum()
+    }
+
+    #[test]
+    fn unsmoothed() {
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_str("I like cats")
+            .feed_str("I like dogs")
+            .feed_str("you like cats too");
+        assert_eq!(chain.smoothing(), Smoothing::None);
+        assert_eq!(chain.log_likelihood_str("you like dogs"), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn smoothing_keeps_counts() {
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_str("I like cats")
+            .feed_str("I like dogs")
+            .feed_str("you like cats too");
+        let mut smoothed = chain.clone();
+        smoothed.set_smoothing(Smoothing::KneserNey(0.75));
+        let mut unsmoothed = smoothed.clone();
+        unsmoothed.set_smoothing(Smoothing::None);
+        assert_eq!(unsmoothed, chain);
+    }
+
+    #[test]
+    fn smoothed_probabilities() {
+        let context = [Some("you".to_owned()), Some("like".to_owned())];
+        for &smoothing in &[
+            Smoothing::Additive(1.0),
+            Smoothing::WittenBell,
+            Smoothing::KneserNey(0.75),
+        ] {
+            let mut chain = Chain::of_order(2);
+            chain
+                .feed_str("I like cats")
+                .feed_str("I like dogs")
+                .feed_str("you like cats too");
+            chain.set_smoothing(smoothing);
+            let seen = chain.probability(&context, Some(&"cats".to_owned()));
+            let unseen = chain.probability(&context, Some(&"dogs".to_owned()));
+            assert!(seen > unseen && unseen > 0.0, "{:?}", smoothing);
+            assert!(chain.log_likelihood_str("you like dogs").is_finite());
+            assert!(chain.perplexity(&[vec!["hamsters".to_owned()]]).is_finite());
+            // A share is reserved for tokens that were never seen.
+            let total = total(&chain, &context);
+            assert!(total < 1.0 && total > 0.5, "{:?}: {}", smoothing, total);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn additive_needs_positive_count() {
+        Chain::<u8>::new().set_smoothing(Smoothing::Additive(0.0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn kneser_ney_needs_discount_below_one() {
+        Chain::<u8>::new().set_smoothing(Smoothing::KneserNey(1.5));
+    }
+
+    #[test]
+    #[cfg(feature = "yaml")]
+    fn load_invalid_smoothing() {
+        let saved = "map: {}\norder: 1\nsmoothing:\n  Additive: 0.0\n";
+        assert!(super::super::yaml::from_str::<Chain<String>>(saved).is_err());
+        let saved = "map: {}\norder: 1\nsmoothing:\n  Additive: 1.0\n";
+        assert!(super::super::yaml::from_str::<Chain<String>>(saved).is_ok());
+    }
+
+    #[test]
+    fn smoothed_sampling() {
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_str("I like cats")
+            .feed_str("I like dogs")
+            .feed_str("you like cats too");
+        chain
+            .set_smoothing(Smoothing::Additive(1.0))
+            .set_smoothed_sampling(true);
+        let vocabulary = ["I", "you", "like", "cats", "dogs", "too"];
+        for _ in 0..20 {
+            for word in chain.generate() {
+                assert!(vocabulary.contains(&&word[..]));
+            }
+        }
+    }
+
+    #[test]
+    fn generate_with_backoff() {
+        let mut chain = Chain::of_order(3);
+        chain.feed_str("I like cats").feed_str("cats are cute");
+        chain.feed_str("my cats are cute too");
+        assert_eq!(chain.generate_str_from_token("are"), "");
+        chain.set_backoff(Backoff::Stupid(0.4));
+        for _ in 0..20 {
+            let generated = chain.generate_str_from_token("are");
+            assert!(["are cute", "are cute too"].contains(&&generated[..]));
+        }
+    }
+
+    #[test]
+    fn stupid_backoff() {
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_str("I like cats")
+            .feed_str("I like dogs")
+            .feed_str("you like cats too");
+        chain.set_backoff(Backoff::Stupid(0.4));
+        let context = [Some("you".to_owned()), Some("like".to_owned())];
+        assert_eq!(chain.probability(&context, Some(&"cats".to_owned())), 1.0);
+        assert_eq!(
+            chain.probability(&context, Some(&"dogs".to_owned())),
+            0.4 / 3.0
+        );
+        assert!(chain.log_likelihood_str("you like hamsters").is_finite());
+    }
+
+    #[test]
+    #[should_panic]
+    fn stupid_backoff_needs_positive_factor() {
+        Chain::<u8>::new().set_backoff(Backoff::Stupid(0.0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn katz_backoff_needs_discount_below_one() {
+        Chain::<u8>::new().set_backoff(Backoff::Katz(1.0));
+    }
+
+    #[test]
+    #[cfg(feature = "yaml")]
+    fn load_invalid_backoff() {
+        let saved = "map: {}\norder: 1\nbackoff:\n  Katz: 1.0\n";
+        assert!(super::super::yaml::from_str::<Chain<String>>(saved).is_err());
+        let saved = "map: {}\norder: 1\nbackoff:\n  Stupid: 0.4\n";
+        assert!(super::super::yaml::from_str::<Chain<String>>(saved).is_ok());
+    }
+
+    #[test]
+    fn katz_backoff() {
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_str("I like cats")
+            .feed_str("I like dogs")
+            .feed_str("you like cats too");
+        chain.set_backoff(Backoff::Katz(0.5));
+        let context = [Some("you".to_owned()), Some("like".to_owned())];
+        assert_eq!(chain.probability(&context, Some(&"cats".to_owned())), 0.5);
+        assert!(chain.probability(&context, Some(&"dogs".to_owned())) > 0.0);
+        let total = total(&chain, &context);
+        assert!(total < 1.0 && total > 0.5, "{}", total);
+        assert!(chain.log_likelihood_str("hamsters like dogs").is_finite());
+    }
+}
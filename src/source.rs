@@ -0,0 +1,185 @@
+//! Generation from and scoring of either kind of chain, so that every way of generating from a
+//! chain is written once for plain and frozen chains alike.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, Result};
+use std::path::Path;
+
+use rand::Rng;
+
+use super::ends::Ends;
+use super::{Chain, Chainable, FrozenChain, Id, Token, UNKNOWN};
+
+/// A chain to generate from, which is either a plain chain or a frozen one.
+pub(crate) enum Source<'a, T: Chainable + 'a> {
+    Chain(&'a Chain<T>),
+    Frozen(&'a FrozenChain<T>),
+}
+
+impl<'a, T: Chainable + 'a> Clone for Source<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T: Chainable + 'a> Copy for Source<'a, T> {}
+
+impl<'a, T> Source<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Gets a chain with the order, the settings and the overlap index of this one.
+    pub(crate) fn settings(self) -> &'a Chain<T> {
+        match self {
+            Source::Chain(chain) => chain,
+            Source::Frozen(frozen) => frozen.settings(),
+        }
+    }
+
+    /// Gets the order of the chain.
+    pub(crate) fn order(self) -> usize {
+        self.settings().order
+    }
+
+    /// Gets the id of the given token, or `UNKNOWN` if it was never fed.
+    pub(crate) fn id(self, token: &Token<T>) -> Token<Id> {
+        match self {
+            Source::Chain(chain) => chain.id(token),
+            Source::Frozen(frozen) => frozen.id(token),
+        }
+    }
+
+    /// Gets the position of the given token in the vocabulary of the chain, if it was ever fed.
+    pub(crate) fn position(self, token: &T) -> Option<usize> {
+        match self.id(&Some(token.clone())) {
+            Some(id) if id != UNKNOWN => Some(id as usize),
+            _ => None,
+        }
+    }
+
+    /// Gets the token with the given id.
+    pub(crate) fn token(self, id: Id) -> &'a T {
+        match self {
+            Source::Chain(chain) => chain.token(id),
+            Source::Frozen(frozen) => frozen.token(id),
+        }
+    }
+
+    /// Gets the token with the given id, if any.
+    pub(crate) fn resolve(self, id: &Token<Id>) -> Option<&'a T> {
+        id.map(|id| self.token(id))
+    }
+
+    /// Samples the token following the given context, which may be shorter than the order of the
+    /// chain. This ends the sequence if the context has no successors.
+    pub(crate) fn next_token<R: Rng + ?Sized>(
+        self,
+        context: &[Token<Id>],
+        rng: &mut R,
+    ) -> Token<Id> {
+        match self {
+            Source::Chain(chain) => chain.next_token(context, rng),
+            Source::Frozen(frozen) => frozen.next_token(context, rng),
+        }
+    }
+
+    /// Samples the token following the given context, with the weight of each successor
+    /// multiplied by the given function. This returns `None` if no successor keeps a positive
+    /// weight.
+    pub(crate) fn next_token_weighted<R, F>(
+        self,
+        context: &[Token<Id>],
+        weight: F,
+        rng: &mut R,
+    ) -> Option<Token<Id>>
+    where
+        R: Rng + ?Sized,
+        F: FnMut(&Token<Id>) -> f64,
+    {
+        match self {
+            Source::Chain(chain) => chain.next_token_weighted(context, weight, rng),
+            Source::Frozen(frozen) => frozen.next_token_weighted(context, weight, rng),
+        }
+    }
+
+    /// Determines whether the end of a sequence can be reached from the given context.
+    pub(crate) fn can_end(self, context: &[Token<Id>], ends: &mut Ends<Vec<Token<Id>>>) -> bool {
+        match self {
+            Source::Chain(chain) => chain.can_end(context, ends),
+            Source::Frozen(frozen) => frozen.can_end(context, ends),
+        }
+    }
+
+    /// Generates a collection of tokens from the chain using the given random number generator.
+    pub(crate) fn generate_with_rng<R: Rng + ?Sized>(self, rng: &mut R) -> Vec<T> {
+        match self {
+            Source::Chain(chain) => chain.generate_with_rng(rng),
+            Source::Frozen(frozen) => frozen.generate_with_rng(rng),
+        }
+    }
+
+    /// Gets every possible successor of the given context along with its probability.
+    pub(crate) fn successors(self, context: &[Token<T>]) -> Vec<(Option<&'a T>, f64)> {
+        match self {
+            Source::Chain(chain) => chain.successors(context),
+            Source::Frozen(frozen) => frozen.successors(context),
+        }
+    }
+
+    /// Gets the probability that `next` follows the given context.
+    pub(crate) fn probability(self, context: &[Token<T>], next: Option<&T>) -> f64 {
+        match self {
+            Source::Chain(chain) => chain.probability(context, next),
+            Source::Frozen(frozen) => frozen.probability(context, next),
+        }
+    }
+
+    /// Computes the natural logarithm of the probability that the chain generates exactly the
+    /// given collection of tokens.
+    pub(crate) fn log_likelihood(self, tokens: &[T]) -> f64 {
+        match self {
+            Source::Chain(chain) => chain.log_likelihood(tokens),
+            Source::Frozen(frozen) => frozen.log_likelihood(tokens),
+        }
+    }
+
+    /// Gets the negative base-2 log-likelihood of a collection of tokens along with the number of
+    /// transitions it contains. Empty collections contribute nothing.
+    fn entropy_terms(self, tokens: &[T]) -> (f64, usize) {
+        if tokens.is_empty() {
+            return (0.0, 0);
+        }
+        (
+            -self.log_likelihood(tokens) / std::f64::consts::LN_2,
+            tokens.len() + 1,
+        )
+    }
+
+    /// Computes the cross-entropy of the chain on the given collections of tokens, in bits per
+    /// transition.
+    pub(crate) fn cross_entropy<S: AsRef<[T]>>(self, sequences: &[S]) -> f64 {
+        let (bits, transitions) = sequences
+            .iter()
+            .map(|tokens| self.entropy_terms(tokens.as_ref()))
+            .fold((0.0, 0), |(b, t), (bits, transitions)| {
+                (b + bits, t + transitions)
+            });
+        bits / transitions as f64
+    }
+}
+
+impl<'a> Source<'a, String> {
+    /// Computes the cross-entropy of the chain, in bits per transition, on a held-out file with
+    /// one sequence of words on each line.
+    pub(crate) fn cross_entropy_file<P: AsRef<Path>>(self, path: P) -> Result<f64> {
+        let reader = BufReader::new(File::open(path)?);
+        let (mut bits, mut transitions) = (0.0, 0);
+        for line in reader.lines() {
+            let (b, t) = self.entropy_terms(&Chain::words(&line?));
+            bits += b;
+            transitions += t;
+        }
+        Ok(bits / transitions as f64)
+    }
+}
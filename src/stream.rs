@@ -0,0 +1,179 @@
+//! Feeding of continuous streams of tokens, which have no start or end.
+
+use std::mem;
+
+use super::{Chain, Chainable, Id};
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Feeds the chain the next tokens of a continuous stream, such as sensor readings or a long
+    /// piece of music. Unlike `feed`, this adds no start or end markers, and only learns the
+    /// transitions between the tokens themselves. The last `order` tokens are kept between calls,
+    /// so that a stream can be fed in chunks of any size and the transitions that span two chunks
+    /// are learned too. Call `end_stream` before feeding an unrelated stream.
+    ///
+    /// As streams never start or end, a chain fed only with streams generates empty collections
+    /// from the start of a sequence, and generation from elsewhere stops as soon as the end of a
+    /// sequence is out of reach. Use `walk` or a walker that restarts from a given state instead.
+    pub fn feed_stream<S: AsRef<[T]>>(&mut self, tokens: S) -> &mut Chain<T> {
+        let tokens = tokens.as_ref();
+        if tokens.is_empty() {
+            return self;
+        }
+        let continued = !self.stream_tail.is_empty();
+        let mut stream = mem::take(&mut self.stream_tail);
+        stream.extend_from_slice(tokens);
+
+        let ids = self.intern_windows(stream.iter().cloned());
+        let toks: Vec<_> = ids.iter().cloned().map(Some).collect();
+        self.add_windows(&toks);
+        self.index(&ids[ids.len() - tokens.len()..], continued);
+
+        let start = stream.len().saturating_sub(self.order);
+        self.stream_tail = stream.split_off(start);
+        self
+    }
+
+    /// Ends the stream fed by `feed_stream`, so that the next tokens fed are the start of a new
+    /// stream rather than a continuation of the last one.
+    pub fn end_stream(&mut self) -> &mut Chain<T> {
+        self.stream_tail.clear();
+        self
+    }
+
+    /// Feeds the chain a collection of tokens that loops, such as a repeating melody, where the
+    /// last token is followed by the first one again. Like `feed_stream`, this adds no start or
+    /// end markers.
+    pub fn feed_cycle<S: AsRef<[T]>>(&mut self, tokens: S) -> &mut Chain<T> {
+        let tokens = tokens.as_ref();
+        if tokens.is_empty() {
+            return self;
+        }
+        let ids = self.intern_windows(
+            tokens
+                .iter()
+                .cycle()
+                .take(tokens.len() + self.order)
+                .cloned(),
+        );
+        let toks: Vec<_> = ids.iter().cloned().map(Some).collect();
+        self.add_windows(&toks);
+        self.index(&ids[..tokens.len()], false);
+        self
+    }
+
+    /// Adds the given tokens to the vocabulary and gets their ids, counting every token after the
+    /// first `order` as the successor of a window. The first tokens of a stream only ever appear
+    /// in contexts, but they were still seen.
+    fn intern_windows<I: Iterator<Item = T>>(&mut self, tokens: I) -> Vec<Id> {
+        tokens
+            .enumerate()
+            .map(|(i, token)| {
+                let count = if i < self.order { 0 } else { 1 };
+                self.intern(token, count)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Backoff, Chain, ConcurrentChain, Limits, Smoothing, TerminalPolicy};
+
+    #[test]
+    fn feed_stream() {
+        let mut whole = Chain::of_order(2);
+        whole.feed_stream(vec![1u8, 2, 3, 1, 2, 4]);
+        assert!(!whole.is_empty());
+        assert!(whole.generate().is_empty());
+        assert_eq!(whole.successors(&[Some(1), Some(2)]).len(), 2);
+        assert!(whole.successors(&[None, None]).is_empty());
+
+        let mut chunked = Chain::of_order(2);
+        chunked
+            .feed_stream(vec![1u8])
+            .feed_stream(vec![2, 3])
+            .feed_stream(vec![1, 2, 4]);
+        assert_eq!(whole, chunked);
+
+        chunked.end_stream().feed_stream(vec![5u8, 6]);
+        assert!(chunked.successors(&[Some(2), Some(4)]).is_empty());
+    }
+
+    #[test]
+    fn feed_cycle() {
+        let mut chain = Chain::new();
+        chain.feed_cycle(vec!["C", "E", "G"]);
+        assert_eq!(chain.successors(&[Some("G")]), vec![(Some(&"C"), 1.0)]);
+        let melody = chain.walk(&["C"], 7, &TerminalPolicy::Stop);
+        assert_eq!(melody, ["E", "G", "C", "E", "G", "C", "E"]);
+
+        let mut chain = Chain::of_order(3);
+        chain.feed_cycle(vec![1u8]);
+        assert_eq!(chain.walk(&[1, 1, 1], 3, &TerminalPolicy::Stop), [1, 1, 1]);
+    }
+
+    #[test]
+    fn generate_from_cycle() {
+        // A cycle never ends, so generation stops right away instead of looping forever.
+        let mut chain = Chain::new();
+        chain.feed_cycle(vec!["C", "E", "G"]);
+        assert_eq!(chain.generate_from_token("C"), ["C"]);
+        assert_eq!(chain.log_likelihood(["C"]), f64::NEG_INFINITY);
+        assert_eq!(chain.generate_from_prefix(&["C"]), Some(vec!["C"]));
+        assert_eq!(
+            chain.freeze().unwrap().generate_from_prefix(&["C"]),
+            Some(vec!["C"])
+        );
+    }
+
+    #[test]
+    fn generate_from_stream() {
+        // Once the stream wanders off into 3s, the end of a sequence is out of reach.
+        let mut chain = Chain::of_order(2);
+        chain.feed(vec![1u8, 2]).feed_stream(vec![1, 2, 3, 3, 3]);
+        let frozen = chain.freeze().unwrap();
+        let concurrent = ConcurrentChain::from(chain.clone());
+        for _ in 0..20 {
+            let limited = chain.generate_with_limits(&Limits::default()).unwrap();
+            for v in &[
+                chain.generate(),
+                frozen.generate(),
+                concurrent.generate(),
+                limited,
+            ] {
+                assert!(v == &[1, 2] || v == &[1, 2, 3], "{:?}", v);
+            }
+        }
+
+        // Sampling only the most likely successor never ends either.
+        let mut chain = Chain::new();
+        chain
+            .feed(vec!["C"])
+            .feed_cycle(vec!["C", "E", "G"])
+            .feed_cycle(vec!["C", "E", "G"])
+            .set_top_k(Some(1));
+        assert!(chain.generate().is_empty());
+        assert!(chain.freeze().unwrap().generate().is_empty());
+    }
+
+    #[test]
+    fn generate_from_stream_with_settings() {
+        // Nothing fed as a stream ends, not even with smoothing or backoff.
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_stream(vec![1u8, 2, 3, 1, 2, 4])
+            .set_smoothing(Smoothing::KneserNey(0.75))
+            .set_smoothed_sampling(true);
+        assert!(chain.generate().is_empty());
+
+        let mut chain = Chain::of_order(2);
+        chain
+            .feed_stream(vec![1u8, 2, 3, 1, 2, 4])
+            .set_backoff(Backoff::Stupid(0.4));
+        assert_eq!(chain.generate_from_token(2), [2]);
+        assert!(chain.generate().is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//! The distinct tokens of a chain, each stored once and identified by its position.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::slice;
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use super::{Chainable, States, Token};
+
+/// Every distinct token of a chain in the order it was first added, with a count for each.
+///
+/// Tokens are only stored in order, and looked up through their hash: a token is keyed by the hash
+/// of itself and a probe number, the first one that is free when it is added. As tokens are never
+/// removed, looking one up tries the same probe numbers in order until it finds the token or a free
+/// key.
+#[derive(Clone)]
+pub(crate) struct Vocabulary<T: Chainable> {
+    tokens: Vec<(Token<T>, usize)>,
+    index: HashMap<u64, usize>,
+    hasher: RandomState,
+}
+
+impl<T> Vocabulary<T>
+where
+    T: Chainable,
+{
+    /// Creates an empty vocabulary.
+    pub(crate) fn new() -> Vocabulary<T> {
+        Vocabulary {
+            tokens: Vec::new(),
+            index: HashMap::new(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Determines whether or not any token has been added to the vocabulary.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Gets the number of distinct tokens in the vocabulary.
+    pub(crate) fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Iterates over the tokens and their counts in the order they were first added.
+    pub(crate) fn iter(&self) -> slice::Iter<'_, (Token<T>, usize)> {
+        self.tokens.iter()
+    }
+
+    /// Gets the token at the given position.
+    pub(crate) fn token(&self, position: usize) -> &Token<T> {
+        &self.tokens[position].0
+    }
+
+    /// Gets the key of the given token for the given probe number.
+    fn key(&self, token: &Token<T>, probe: usize) -> u64 {
+        self.hasher.hash_one((probe, token))
+    }
+
+    /// Looks up the given token, getting either its position or the first free key for it.
+    fn find(&self, token: &Token<T>) -> Result<usize, u64> {
+        let mut probe = 0;
+        loop {
+            let key = self.key(token, probe);
+            match self.index.get(&key) {
+                Some(&position) if self.tokens[position].0 == *token => return Ok(position),
+                Some(_) => probe += 1,
+                None => return Err(key),
+            }
+        }
+    }
+
+    /// Gets the position of the given token, if it was ever added.
+    pub(crate) fn position(&self, token: &Token<T>) -> Option<usize> {
+        self.find(token).ok()
+    }
+
+    /// Adds to the count of a token, adding the token itself if it is new, and gets its position.
+    pub(crate) fn add(&mut self, token: Token<T>, count: usize) -> usize {
+        match self.find(&token) {
+            Ok(position) => {
+                self.tokens[position].1 += count;
+                position
+            }
+            Err(key) => {
+                let position = self.tokens.len();
+                self.index.insert(key, position);
+                self.tokens.push((token, count));
+                position
+            }
+        }
+    }
+}
+
+impl<T> IntoIterator for Vocabulary<T>
+where
+    T: Chainable,
+{
+    type Item = (Token<T>, usize);
+    type IntoIter = ::std::vec::IntoIter<(Token<T>, usize)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tokens.into_iter()
+    }
+}
+
+impl<T> Default for Vocabulary<T>
+where
+    T: Chainable,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> PartialEq for Vocabulary<T>
+where
+    T: Chainable,
+{
+    fn eq(&self, other: &Vocabulary<T>) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(token, count)| match other.position(token) {
+                    Some(position) => other.tokens[position].1 == *count,
+                    None => false,
+                })
+    }
+}
+
+impl<T> fmt::Debug for Vocabulary<T>
+where
+    T: Chainable + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.iter().map(|(token, count)| (token, count)))
+            .finish()
+    }
+}
+
+impl<T> Serialize for Vocabulary<T>
+where
+    T: Chainable + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (token, count) in self.iter() {
+            map.serialize_entry(token, count)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Vocabulary<T>
+where
+    T: Chainable + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // A vocabulary is saved in the same format as a collection of states.
+        let states = States::deserialize(deserializer)?;
+        let mut vocabulary = Vocabulary::new();
+        for (token, count) in states.tokens {
+            vocabulary.add(token, count);
+        }
+        Ok(vocabulary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Vocabulary;
+
+    #[test]
+    fn add() {
+        let mut vocabulary = Vocabulary::new();
+        assert!(vocabulary.is_empty());
+        assert_eq!(vocabulary.add(Some("cats"), 1), 0);
+        assert_eq!(vocabulary.add(None, 2), 1);
+        assert_eq!(vocabulary.add(Some("cats"), 3), 0);
+        assert_eq!(vocabulary.len(), 2);
+        assert_eq!(vocabulary.position(&Some("cats")), Some(0));
+        assert_eq!(vocabulary.position(&Some("dogs")), None);
+        assert_eq!(vocabulary.token(1), &None);
+        let tokens: Vec<_> = vocabulary.into_iter().collect();
+        assert_eq!(tokens, vec![(Some("cats"), 4), (None, 2)]);
+    }
+}
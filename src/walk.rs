@@ -0,0 +1,329 @@
+//! Step-by-step generation from a chain.
+
+use rand::rngs::ThreadRng;
+use rand::{thread_rng, Rng};
+
+use super::source::Source;
+use super::{Chain, Chainable, FrozenChain, Id, Token};
+
+/// What a random walk does when it reaches the end of a sequence, or a state that was never
+/// followed by anything.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TerminalPolicy<T> {
+    /// End the walk early.
+    Stop,
+    /// Continue from the start of a new sequence.
+    Restart,
+    /// Continue from the given state, as in `Chain::walk`.
+    RestartFrom(Vec<T>),
+}
+
+/// A cursor that walks a Markov chain one token at a time, created by `Chain::walker` or
+/// `FrozenChain::walker`.
+///
+/// Tokens are sampled lazily as the walker is iterated, so the caller can inspect the current
+/// context between steps and steer the walk by injecting tokens of its own. The walker yields
+/// `None` once it reaches the end of a sequence, until a token is injected or it is restarted.
+///
+/// ```
+/// use markov::Chain;
+///
+/// let mut chain = Chain::new();
+/// chain.feed_str("I like cats").feed_str("I hate dogs");
+/// let mut walker = chain.walker();
+/// assert_eq!(walker.next().unwrap(), "I");
+/// walker.override_next("hate".to_owned());
+/// let rest: Vec<_> = walker.collect();
+/// assert_eq!(rest, ["hate", "dogs"]);
+/// ```
+pub struct Walker<'a, T: Chainable + 'a, R: Rng = ThreadRng> {
+    chain: Source<'a, T>,
+    context: Vec<Token<T>>,
+    /// The ids of the tokens of the context.
+    ids: Vec<Token<Id>>,
+    next: Option<T>,
+    finished: bool,
+    rng: R,
+}
+
+impl<'a, T, R> Walker<'a, T, R>
+where
+    T: Chainable + 'a,
+    R: Rng,
+{
+    /// Gets the context the next token will be sampled from, i.e. the last `order` tokens, where
+    /// `None` marks the start of a sequence.
+    pub fn context(&self) -> &[Option<T>] {
+        &self.context
+    }
+
+    /// Determines whether the walker has reached the end of a sequence.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Moves the walker past the given token as if it had been generated, without yielding it.
+    /// This resumes a walker that reached the end of a sequence.
+    pub fn inject(&mut self, token: T) -> &mut Walker<'a, T, R> {
+        let token = Some(token);
+        self.ids.remove(0);
+        self.ids.push(self.chain.id(&token));
+        self.context.remove(0);
+        self.context.push(token);
+        self.finished = false;
+        self
+    }
+
+    /// Makes the walker yield the given token next instead of sampling one. This resumes a walker
+    /// that reached the end of a sequence.
+    pub fn override_next(&mut self, token: T) -> &mut Walker<'a, T, R> {
+        self.next = Some(token);
+        self.finished = false;
+        self
+    }
+
+    /// Moves the walker back to the start of a new sequence.
+    pub fn restart(&mut self) -> &mut Walker<'a, T, R> {
+        self.context = vec![None; self.chain.order()];
+        self.ids = vec![None; self.chain.order()];
+        self.next = None;
+        self.finished = false;
+        self
+    }
+
+    /// Moves the walker to the start of a new sequence that begins with the last `order` tokens
+    /// of the given state.
+    pub fn restart_from(&mut self, state: &[T]) -> &mut Walker<'a, T, R> {
+        self.restart();
+        for token in &state[state.len().saturating_sub(self.chain.order())..] {
+            self.inject(token.clone());
+        }
+        self
+    }
+}
+
+impl<'a, T, R> Iterator for Walker<'a, T, R>
+where
+    T: Chainable + 'a,
+    R: Rng,
+{
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        if self.finished {
+            return None;
+        }
+        let next = match self.next.take() {
+            Some(next) => Some(next),
+            None => {
+                let id = self.chain.next_token(&self.ids, &mut self.rng);
+                self.chain.resolve(&id).cloned()
+            }
+        };
+        match next {
+            Some(next) => {
+                self.inject(next.clone());
+                Some(next)
+            }
+            None => {
+                self.finished = true;
+                None
+            }
+        }
+    }
+}
+
+impl<'a, T> Source<'a, T>
+where
+    T: Chainable + 'a,
+{
+    /// Creates a walker over the chain that draws from the given random number generator.
+    fn walker<R: Rng>(self, rng: R) -> Walker<'a, T, R> {
+        Walker {
+            chain: self,
+            context: vec![None; self.order()],
+            ids: vec![None; self.order()],
+            next: None,
+            finished: false,
+            rng,
+        }
+    }
+
+    /// Performs a random walk over the chain, as `Chain::walk_with_rng` does.
+    fn walk<R: Rng + ?Sized>(
+        self,
+        start: &[T],
+        steps: usize,
+        policy: &TerminalPolicy<T>,
+        rng: &mut R,
+    ) -> Vec<T> {
+        let mut walker = self.walker(rng);
+        walker.restart_from(start);
+        let mut ret = Vec::with_capacity(steps);
+        let mut restarted = false;
+        while ret.len() < steps {
+            match walker.next() {
+                Some(token) => {
+                    ret.push(token);
+                    restarted = false;
+                }
+                None if restarted => break,
+                None => {
+                    match *policy {
+                        TerminalPolicy::Stop => break,
+                        TerminalPolicy::Restart => walker.restart(),
+                        TerminalPolicy::RestartFrom(ref state) => walker.restart_from(state),
+                    };
+                    restarted = true;
+                }
+            }
+        }
+        ret
+    }
+}
+
+impl<T> Chain<T>
+where
+    T: Chainable,
+{
+    /// Creates a walker that generates a sequence from the chain one token at a time.
+    pub fn walker(&self) -> Walker<'_, T> {
+        self.walker_with_rng(thread_rng())
+    }
+
+    /// Creates a walker that generates a sequence from the chain one token at a time, drawing
+    /// from the given random number generator.
+    pub fn walker_with_rng<R: Rng>(&self, rng: R) -> Walker<'_, T, R> {
+        Source::Chain(self).walker(rng)
+    }
+
+    /// Performs a random walk of `steps` tokens over the observed states of the chain, ignoring
+    /// the boundaries between sequences according to the given policy. The walk starts from the
+    /// last `order` tokens of `start`, as if a sequence had begun with them, and an empty `start`
+    /// starts from the beginning of a sequence. The returned tokens do not include `start`, and
+    /// there are fewer than `steps` of them if the walk stops early, or if it is stuck because
+    /// the state it restarts from has no successors.
+    pub fn walk(&self, start: &[T], steps: usize, policy: &TerminalPolicy<T>) -> Vec<T> {
+        self.walk_with_rng(start, steps, policy, &mut thread_rng())
+    }
+
+    /// Performs a random walk of `steps` tokens over the observed states of the chain, using the
+    /// given random number generator. See `walk` for details.
+    pub fn walk_with_rng<R: Rng + ?Sized>(
+        &self,
+        start: &[T],
+        steps: usize,
+        policy: &TerminalPolicy<T>,
+        rng: &mut R,
+    ) -> Vec<T> {
+        Source::Chain(self).walk(start, steps, policy, rng)
+    }
+}
+
+impl<T> FrozenChain<T>
+where
+    T: Chainable,
+{
+    /// Creates a walker that generates a sequence from the chain one token at a time, as in
+    /// `Chain::walker`.
+    pub fn walker(&self) -> Walker<'_, T> {
+        self.walker_with_rng(thread_rng())
+    }
+
+    /// Creates a walker that generates a sequence from the chain one token at a time, drawing
+    /// from the given random number generator.
+    pub fn walker_with_rng<R: Rng>(&self, rng: R) -> Walker<'_, T, R> {
+        Source::Frozen(self).walker(rng)
+    }
+
+    /// Performs a random walk of `steps` tokens over the observed states of the chain, as in
+    /// `Chain::walk`.
+    pub fn walk(&self, start: &[T], steps: usize, policy: &TerminalPolicy<T>) -> Vec<T> {
+        self.walk_with_rng(start, steps, policy, &mut thread_rng())
+    }
+
+    /// Performs a random walk of `steps` tokens over the observed states of the chain, using the
+    /// given random number generator.
+    pub fn walk_with_rng<R: Rng + ?Sized>(
+        &self,
+        start: &[T],
+        steps: usize,
+        policy: &TerminalPolicy<T>,
+        rng: &mut R,
+    ) -> Vec<T> {
+        Source::Frozen(self).walk(start, steps, policy, rng)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Chain;
+    use super::TerminalPolicy;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn walker() {
+        let mut chain = Chain::of_order(2);
+        chain.feed(vec![1u8, 2, 3]).feed(vec![4, 2, 5]);
+        let walked: Vec<_> = chain.walker_with_rng(StdRng::seed_from_u64(7)).collect();
+        assert_eq!(
+            walked,
+            chain.generate_with_rng(&mut StdRng::seed_from_u64(7))
+        );
+        let frozen = chain.freeze().unwrap();
+        assert_eq!(
+            frozen
+                .walker_with_rng(StdRng::seed_from_u64(7))
+                .collect::<Vec<_>>(),
+            walked
+        );
+
+        let mut walker = chain.walker();
+        assert_eq!(walker.context(), &[None, None]);
+        walker.inject(4);
+        assert_eq!(walker.context(), &[None, Some(4)]);
+        assert_eq!(walker.next(), Some(2));
+        assert_eq!(walker.next(), Some(5));
+        assert_eq!(walker.next(), None);
+        assert!(walker.is_finished());
+        assert_eq!(walker.next(), None);
+
+        walker.restart().inject(1).override_next(2);
+        assert_eq!(walker.by_ref().collect::<Vec<_>>(), vec![2, 3]);
+        walker.override_next(9);
+        assert_eq!(walker.next(), Some(9));
+        assert_eq!(walker.context(), &[Some(3), Some(9)]);
+        assert_eq!(walker.next(), None);
+    }
+
+    #[test]
+    fn walk() {
+        let mut chain = Chain::new();
+        chain
+            .feed(vec!["sun", "sun", "rain", "sun"])
+            .feed(vec!["rain", "fog"]);
+        for _ in 0..20 {
+            let stop = chain.walk(&["sun"], 50, &TerminalPolicy::Stop);
+            // Only sun and fog can end a sequence.
+            assert!(stop.len() == 50 || stop.last().map_or(true, |&t| t == "sun" || t == "fog"));
+
+            let restart = chain.walk(&["fog"], 50, &TerminalPolicy::Restart);
+            assert_eq!(restart.len(), 50);
+            let from = chain.walk(&[], 50, &TerminalPolicy::RestartFrom(vec!["rain"]));
+            assert_eq!(from.len(), 50);
+            for pair in from.windows(2) {
+                // After fog, the walk continues from rain, which is never followed by rain.
+                assert!(pair != ["fog", "rain"], "{:?}", from);
+            }
+        }
+        let stuck = chain.walk(&["snow"], 50, &TerminalPolicy::RestartFrom(vec!["snow"]));
+        assert!(stuck.is_empty());
+        let frozen = chain.freeze().unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut frozen_rng = StdRng::seed_from_u64(1);
+        assert_eq!(
+            frozen.walk_with_rng(&["fog"], 50, &TerminalPolicy::Restart, &mut frozen_rng),
+            chain.walk_with_rng(&["fog"], 50, &TerminalPolicy::Restart, &mut rng)
+        );
+    }
+}